@@ -0,0 +1,149 @@
+use std::{cell::Cell, rc::Rc, sync::Arc, time::Duration};
+
+use gtk4::glib;
+use x11rb::protocol::xproto::ModMask;
+
+use crate::{
+    config::{self, KrowbarConfig, Modifier, Position},
+    xbackend::{Geometry, Monitor, X11Backend},
+};
+
+const SLIDE_STEPS: i32 = 8;
+const SLIDE_STEP: Duration = Duration::from_millis(15);
+
+/// Slides the bar out of view, leaving a thin strip at the monitor edge, which brings it back
+/// on hover. The bar stays revealed while the configured modifier is held or a popup is open.
+pub struct Autohide {
+    x11: Arc<X11Backend>,
+    win: u32,
+    bar: Geometry,
+    /// The bar is clipped to it while sliding, so it doesn't show up on a neighbouring monitor.
+    monitor: Geometry,
+    position: Position,
+    cfg: config::Autohide,
+    /// Pixels the bar is slid out of view, `hidden_offset` when fully hidden.
+    offset: Rc<Cell<i32>>,
+    hidden_offset: i32,
+    slide: Option<glib::JoinHandle<()>>,
+    hovered: bool,
+    modifier_held: bool,
+    pinned: bool,
+}
+
+impl Autohide {
+    pub fn new(x11: Arc<X11Backend>, win: u32, monitor: &Monitor, cfg: &KrowbarConfig) -> Self {
+        let hidden_offset = monitor
+            .bar_reserved_space(&cfg.bar)
+            .saturating_sub(cfg.autohide.trigger_size) as i32;
+
+        let mut autohide = Autohide {
+            x11,
+            win,
+            bar: monitor.bar_geometry(&cfg.bar),
+            monitor: monitor.geometry(),
+            position: cfg.bar.position.clone(),
+            cfg: cfg.autohide.clone(),
+            offset: Rc::new(Cell::new(0)),
+            hidden_offset,
+            slide: None,
+            hovered: false,
+            modifier_held: false,
+            pinned: false,
+        };
+        // Shown on startup, slides away after the delay
+        autohide.update();
+        autohide
+    }
+
+    pub fn set_hovered(&mut self, hovered: bool) {
+        self.hovered = hovered;
+        self.update();
+    }
+
+    pub fn set_modifiers(&mut self, mods: u16) {
+        let modifier_held = self
+            .cfg
+            .modifier
+            .is_some_and(|modifier| mods & u16::from(mod_mask(modifier)) != 0);
+
+        if modifier_held != self.modifier_held {
+            self.modifier_held = modifier_held;
+            self.update();
+        }
+    }
+
+    /// Keeps the bar revealed, e.g. while the calendar is open.
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = pinned;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let reveal = self.hovered || self.modifier_held || self.pinned;
+        let (target, delay) = match reveal {
+            true => (0, Duration::ZERO),
+            false => (
+                self.hidden_offset,
+                Duration::from_millis(self.cfg.hide_delay_ms),
+            ),
+        };
+
+        if let Some(slide) = self.slide.take() {
+            slide.abort();
+        }
+        if reveal {
+            if let Err(err) = self.x11.raise(self.win) {
+                log::error!("Failed to raise the bar {:?}", err);
+            }
+        }
+        if self.offset.get() == target {
+            return;
+        }
+
+        let x11 = self.x11.clone();
+        let (win, bar, monitor) = (self.win, self.bar, self.monitor);
+        let position = self.position.clone();
+        let offset = self.offset.clone();
+        self.slide = Some(glib::spawn_future_local(async move {
+            glib::timeout_future(delay).await;
+
+            let start = offset.get();
+            for step in 1..=SLIDE_STEPS {
+                let current = start + (target - start) * step / SLIDE_STEPS;
+                offset.set(current);
+                if let Err(err) = place(&x11, win, bar, monitor, &position, current) {
+                    log::error!("Failed to slide the bar {:?}", err);
+                    return;
+                }
+                glib::timeout_future(SLIDE_STEP).await;
+            }
+        }));
+    }
+}
+
+fn place(
+    x11: &X11Backend,
+    win: u32,
+    bar: Geometry,
+    monitor: Geometry,
+    position: &Position,
+    offset: i32,
+) -> anyhow::Result<()> {
+    let y = match position {
+        Position::Top => bar.y as i32 - offset,
+        Position::Bottom => bar.y as i32 + offset,
+    };
+
+    x11.clip_window(win, bar.x.into(), y, monitor)?;
+    x11.place_window_at(win, bar.x.into(), y)
+}
+
+// XXX: Assumes the usual modifier mapping, Alt being Mod1 and Super being Mod4
+fn mod_mask(modifier: Modifier) -> ModMask {
+    match modifier {
+        Modifier::Shift => ModMask::SHIFT,
+        Modifier::Control => ModMask::CONTROL,
+        Modifier::Alt => ModMask::M1,
+        Modifier::Super => ModMask::M4,
+    }
+}
@@ -1,20 +1,29 @@
 use anyhow::anyhow;
 use gtk4::{self as gtk};
 use std::{
-    collections::HashSet, rc::Rc, sync::{Arc, Mutex}, time::Duration
+    cell::Cell, collections::HashSet, rc::Rc, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}
 };
+use serde::Serialize;
 use tinytemplate::TinyTemplate;
+use tokio::signal::unix::{signal, SignalKind};
 
-use gtk::{gdk, prelude::*, Application, ApplicationWindow};
-use sysinfo::{Disks, Networks, System};
+use gtk::{gdk, gio, prelude::*, Application, ApplicationWindow};
+use sysinfo::{Components, Disks, Networks, System};
 use xbackend::X11Backend;
 
 use crate::{
+    autohide::Autohide,
     bspwm::{listen_to_bspwm, BspwmState, DesktopState, MonitorState},
-    config::{KrowbarConfig, Position},
+    config::{self, KrowbarConfig, Position, Reserve},
+    instruments::{
+        mpris::{MprisClient, PlayerState},
+        pomodoro::Pomodoro,
+        sni::{self, ItemState, SniClient},
+    },
+    ipc::{self, IpcCommand},
     widgets::*,
-    xbackend::{self, Monitor},
-    Args, Widget,
+    xbackend::{self, Monitor, TrayEvent, X11Event},
+    xdg, Args, TimerAction, Widget,
 };
 
 pub const FOCUSED_DESKTOP: &str = "focused-desktop";
@@ -25,28 +34,60 @@ pub const EMPTY_DESKTOP: &str = "empty-desktop";
 #[derive(Clone, Debug)]
 enum BarEvent {
     ClockClick,
+    ClockScroll(f64),
+    CalendarClosed,
+    CalendarChanged,
+    TimerToggle,
+    TimerReset,
+    TimerAdjust(f64),
+    MprisPlayPause,
+    MprisScroll(f64),
+    SniItems(Vec<ItemState>),
+    NotificationsChanged,
+    NotificationsToggle,
+    KeyboardClick,
+    BacklightChanged,
+    BacklightScroll(f64),
+    /// Ends the bar's event loop, on reload.
+    Close,
 }
 
 #[derive(Clone, Debug)]
 pub enum SystemEvent {
     Tick,
     SlowTick,
+    TimerUpdate,
+    Tray(TrayEvent),
+    KeyboardUpdate,
+    ModifiersUpdate(u16),
+    MprisUpdate(Vec<PlayerState>),
     DesktopStateUpdateNew(MonitorState),
     DesktopLayoutChange(DesktopState),
 }
 
 struct Bar {
-    monitor_name: String,
+    monitor: Monitor,
+    cfg: KrowbarConfig,
     desktop_buttons: DesktopButtons,
     win_count: WinCount,
     active_node: ActiveNode,
     network: Network,
     cpu: Cpu,
+    load: Option<Load>,
     mem: Mem,
     storage: Storage,
+    disk_io: Option<DiskIo>,
     bat: Batteries,
     clock: Clock,
     volume: Volume,
+    timer: Timer,
+    mpris: Option<Mpris>,
+    tray: Option<Tray>,
+    autohide: Option<Rc<Mutex<Autohide>>>,
+    sni: Option<Sni>,
+    notifications: Option<Notifications>,
+    keyboard: Option<Keyboard>,
+    backlight: Option<Backlight>,
     bar_box: gtk::CenterBox,
 }
 
@@ -54,7 +95,11 @@ pub struct Instruments {
     pub sys: System,
     pub networks: Networks,
     pub disks: Disks,
+    pub components: Components,
     pub bat_manager: battery::Manager,
+    pub pomodoro: Pomodoro,
+    /// Only connected while the MPRIS widget is enabled.
+    pub mpris: Option<Rc<MprisClient>>,
 }
 
 impl Bar {
@@ -65,34 +110,10 @@ impl Bar {
         sender: async_channel::Sender<BarEvent>,
         monitor: &Monitor,
         args: &Args,
+        cfg: &KrowbarConfig,
     ) -> Self {
         let bar_box = gtk::CenterBox::builder().build();
 
-        let disabled_widgets: HashSet<Widget> = args
-            .disabled_widgets
-            .clone()
-            .unwrap_or_default()
-            .into_iter()
-            .collect();
-        let enabled_widgets: Vec<Widget> = args
-            .enabled_widgets
-            .clone()
-            .unwrap_or(vec![
-                Widget::Desktops,
-                Widget::WinCount,
-                Widget::FocusedName,
-                Widget::Network,
-                Widget::Cpu,
-                Widget::Mem,
-                Widget::Disk,
-                Widget::Bat,
-                Widget::Volume,
-                Widget::Clock,
-            ])
-            .into_iter()
-            .filter(|widget| !disabled_widgets.contains(widget))
-            .collect();
-
         let box_left = gtk::Box::builder()
             .halign(gtk::Align::Start)
             .orientation(gtk::Orientation::Horizontal)
@@ -114,31 +135,59 @@ impl Bar {
             sys,
             networks,
             disks,
+            components,
             bat_manager,
+            pomodoro,
+            mpris,
         } = &mut *instruments.lock().expect("instruments mutex");
 
+        let widgets = enabled_widgets(args);
         let desktop_buttons = DesktopButtons::new(monitor_state);
         let win_count = WinCount::new(monitor_state);
         let active_node = ActiveNode::new(x11.clone(), monitor_state);
         let network = Network::new(networks);
-        let cpu = Cpu::new(sys);
-        let mem = Mem::new(sys);
-        let storage = Storage::new(disks);
+        let cpu = Cpu::new(sys, components, &cfg.cpu);
+        let load = widgets
+            .contains(&Widget::Load)
+            .then(|| Load::new(sys, &cfg.load));
+        let mem = Mem::new(sys, &cfg.mem);
+        let storage = Storage::new(disks, &cfg.storage);
+        let disk_io = widgets
+            .contains(&Widget::DiskIo)
+            .then(|| DiskIo::new(&cfg.disk_io));
         let bat = Batteries::new(bat_manager).expect("Bat widget");
-        let clock = Clock::new();
+        let mut clock = Clock::new(&cfg.clock);
         let volume = Volume::new();
+        let timer = Timer::new(pomodoro);
+        let mpris = mpris.clone().map(|client| Mpris::new(client, &cfg.mpris));
+        let sni = if widgets.contains(&Widget::Sni) {
+            Sni::new(cfg)
+                .inspect_err(|err| log::error!("Failed to connect to the session bus {err}"))
+                .ok()
+        } else {
+            None
+        };
+        let notifications = widgets
+            .contains(&Widget::Notifications)
+            .then(Notifications::new);
+        let keyboard = (widgets.contains(&Widget::Keyboard) && x11.xkb)
+            .then(|| Keyboard::new(x11.clone(), &cfg.keyboard));
+        let mut backlight = widgets
+            .contains(&Widget::Backlight)
+            .then(|| Backlight::new(&cfg.backlight));
 
         //XXX: nasty hack. Avoids separators where they are not needed
         let mut sep_added = false;
         let mut add_sep = || {
             if sep_added {
-                add_separator(&box_right);
+                Some(add_separator(&box_right))
             } else {
                 sep_added = true;
+                None
             }
         };
 
-        for enabled in enabled_widgets {
+        for enabled in widgets.iter().copied() {
             match enabled {
                 Widget::Desktops => {
                     for button in &desktop_buttons.buttons {
@@ -158,14 +207,29 @@ impl Bar {
                 Widget::Cpu => {
                     add_sep();
                     box_right.append(&cpu.button);
+                    if let Some(temp) = &cpu.temp {
+                        box_right.append(temp);
+                    }
+                }
+                Widget::Load => {
+                    if let Some(load) = &load {
+                        add_sep();
+                        box_right.append(&load.button);
+                    }
                 }
                 Widget::Mem => {
                     add_sep();
                     box_right.append(&mem.button);
                 }
                 Widget::Disk => {
-                    add_sep();
-                    box_right.append(&storage.button);
+                    hide_with(&storage.container, add_sep());
+                    box_right.append(&storage.container);
+                }
+                Widget::DiskIo => {
+                    if let Some(disk_io) = &disk_io {
+                        add_sep();
+                        box_right.append(&disk_io.label);
+                    }
                 }
                 Widget::Bat => {
                     for bat_btn in bat.buttons.iter() {
@@ -177,44 +241,264 @@ impl Bar {
                     add_sep();
                     box_right.append(&volume.label);
                 }
+                Widget::Timer => {
+                    add_sep();
+                    box_right.append(&timer.button);
+                }
+                Widget::Mpris => {
+                    if let Some(mpris) = &mpris {
+                        hide_with(&mpris.button, add_sep());
+                        box_right.append(&mpris.button);
+                    }
+                }
                 Widget::Clock => {
                     box_right.append(&clock.button);
                 }
+                // Not a GTK widget, see `Tray`
+                Widget::Tray => {}
+                Widget::Sni => {
+                    if let Some(sni) = &sni {
+                        hide_with(&sni.container, add_sep());
+                        box_right.append(&sni.container);
+                    }
+                }
+                Widget::Notifications => {
+                    if let Some(notifications) = &notifications {
+                        add_sep();
+                        box_right.append(&notifications.button);
+                    }
+                }
+                Widget::Keyboard => {
+                    if let Some(keyboard) = &keyboard {
+                        add_sep();
+                        box_right.append(&keyboard.button);
+                    }
+                }
+                Widget::Backlight => {
+                    if let Some(backlight) = &backlight {
+                        add_sep();
+                        box_right.append(&backlight.label);
+                    }
+                }
             }
         }
 
+        let timer_sender = sender.clone();
+        timer.button.connect_clicked(move |_| {
+            let _ = timer_sender.send_blocking(BarEvent::TimerToggle);
+        });
+
+        let timer_reset_sender = sender.clone();
+        let timer_right_click = gtk::GestureClick::builder().button(3).build();
+        timer_right_click.connect_released(move |_, _, _, _| {
+            let _ = timer_reset_sender.send_blocking(BarEvent::TimerReset);
+        });
+        timer.button.add_controller(timer_right_click);
+
+        let timer_scroll_sender = sender.clone();
+        let timer_scroll =
+            gtk::EventControllerScroll::new(gtk::EventControllerScrollFlags::VERTICAL);
+        timer_scroll.connect_scroll(move |_, _, dy| {
+            let _ = timer_scroll_sender.try_send(BarEvent::TimerAdjust(dy));
+            gtk::glib::Propagation::Stop
+        });
+        timer.button.add_controller(timer_scroll);
+
+        if let Some(mpris) = &mpris {
+            let mpris_sender = sender.clone();
+            mpris.button.connect_clicked(move |_| {
+                let _ = mpris_sender.send_blocking(BarEvent::MprisPlayPause);
+            });
+
+            let mpris_scroll_sender = sender.clone();
+            let mpris_scroll =
+                gtk::EventControllerScroll::new(gtk::EventControllerScrollFlags::VERTICAL);
+            mpris_scroll.connect_scroll(move |_, _, dy| {
+                let _ = mpris_scroll_sender.try_send(BarEvent::MprisScroll(dy));
+                gtk::glib::Propagation::Stop
+            });
+            mpris.button.add_controller(mpris_scroll);
+        }
+
+        if let Some(sni) = &sni {
+            listen_to_sni(sni.client.clone(), sender.clone());
+        }
+
+        if let Some(notifications) = &notifications {
+            let notifications_sender = sender.clone();
+            notifications.button.connect_clicked(move |_| {
+                let _ = notifications_sender.send_blocking(BarEvent::NotificationsToggle);
+            });
+
+            let notifications_change_sender = sender.clone();
+            notifications.watch(move || {
+                let _ = notifications_change_sender.try_send(BarEvent::NotificationsChanged);
+            });
+        }
+
+        if let Some(keyboard) = &keyboard {
+            let keyboard_sender = sender.clone();
+            keyboard.button.connect_clicked(move |_| {
+                let _ = keyboard_sender.send_blocking(BarEvent::KeyboardClick);
+            });
+        }
+
+        if let Some(backlight) = &mut backlight {
+            let backlight_scroll_sender = sender.clone();
+            let backlight_scroll =
+                gtk::EventControllerScroll::new(gtk::EventControllerScrollFlags::VERTICAL);
+            backlight_scroll.connect_scroll(move |_, _, dy| {
+                let _ = backlight_scroll_sender.try_send(BarEvent::BacklightScroll(dy));
+                gtk::glib::Propagation::Stop
+            });
+            backlight.label.add_controller(backlight_scroll);
+
+            let backlight_change_sender = sender.clone();
+            backlight.watch(move || {
+                let _ = backlight_change_sender.try_send(BarEvent::BacklightChanged);
+            });
+        }
+
+        let scroll_sender = sender.clone();
+        let popup_sender = sender.clone();
+        let calendar_sender = sender.clone();
         clock.button.connect_clicked(move |_| {
             let _ = sender.clone().send_blocking(BarEvent::ClockClick);
         });
 
+        let clock_scroll =
+            gtk::EventControllerScroll::new(gtk::EventControllerScrollFlags::VERTICAL);
+        clock_scroll.connect_scroll(move |_, _, dy| {
+            // Dropping scroll events while the channel is busy is fine
+            let _ = scroll_sender.try_send(BarEvent::ClockScroll(dy));
+            gtk::glib::Propagation::Stop
+        });
+        clock.button.add_controller(clock_scroll);
+
+        clock.popup.connect_hide(move |_| {
+            let _ = popup_sender.try_send(BarEvent::CalendarClosed);
+        });
+
+        clock.watch_calendars(move || {
+            // A sync touches many files at once, dropping some of the events is fine
+            let _ = calendar_sender.try_send(BarEvent::CalendarChanged);
+        });
+
+        if !cfg.errors.is_empty() {
+            let errors = gtk::Label::builder()
+                .css_name("krowbar-error")
+                .label(cfg.errors.join(" | "))
+                .tooltip_text(cfg.errors.join("\n"))
+                .ellipsize(gtk::pango::EllipsizeMode::End)
+                .max_width_chars(80)
+                .build();
+            box_center.append(&errors);
+        }
+
         bar_box.set_start_widget(Some(&box_left));
         bar_box.set_center_widget(Some(&box_center));
         bar_box.set_end_widget(Some(&box_right));
 
         Bar {
-            monitor_name: monitor.name.clone(),
+            monitor: monitor.clone(),
+            cfg: cfg.clone(),
             desktop_buttons,
             win_count,
             active_node,
             storage,
+            disk_io,
             network,
             cpu,
+            load,
             mem,
             bat,
             clock,
             volume,
+            timer,
+            mpris,
+            tray: None,
+            autohide: None,
+            sni,
+            notifications,
+            keyboard,
+            backlight,
             bar_box,
         }
     }
 }
 
-fn add_separator(gtk_box: &gtk::Box) {
-    gtk_box.append(
-        &gtk::Label::builder()
-            .label("|")
-            .css_name("separator")
-            .build(),
-    )
+fn enabled_widgets(args: &Args) -> Vec<Widget> {
+    let disabled_widgets: HashSet<Widget> = args
+        .disabled_widgets
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+
+    args.enabled_widgets
+        .clone()
+        .unwrap_or(vec![
+            Widget::Desktops,
+            Widget::WinCount,
+            Widget::FocusedName,
+            Widget::Network,
+            Widget::Cpu,
+            Widget::Mem,
+            Widget::Disk,
+            Widget::Bat,
+            Widget::Volume,
+            Widget::Clock,
+        ])
+        .into_iter()
+        .filter(|widget| !disabled_widgets.contains(widget))
+        .collect()
+}
+
+/// The tray can be hosted by a single bar only.
+fn is_tray_monitor(x11: &X11Backend, monitor: &Monitor, cfg: &KrowbarConfig) -> bool {
+    match &cfg.tray.monitor {
+        Some(name) => *name == monitor.name,
+        None => x11
+            .primary_monitor()
+            .is_some_and(|primary| primary.name == monitor.name),
+    }
+}
+
+pub fn add_separator(gtk_box: &gtk::Box) -> gtk::Label {
+    let separator = gtk::Label::builder()
+        .label("|")
+        .css_name("separator")
+        .build();
+    gtk_box.append(&separator);
+    separator
+}
+
+/// Keeps the separator in front of a widget hidden while the widget is, e.g. when it is empty.
+fn hide_with(widget: &impl IsA<gtk::Widget>, separator: Option<gtk::Label>) {
+    if let Some(separator) = separator {
+        widget
+            .bind_property("visible", &separator, "visible")
+            .sync_create()
+            .build();
+    }
+}
+
+/// Hides or restacks the bar while the desktop shown on its monitor has a fullscreen node.
+fn follow_fullscreen(
+    x11: &X11Backend,
+    x11_win: u32,
+    window: &ApplicationWindow,
+    mode: &config::Fullscreen,
+    covered: bool,
+) -> anyhow::Result<()> {
+    match mode {
+        config::Fullscreen::Hide => window.set_visible(!covered),
+        config::Fullscreen::StayOnTop => x11.raise(x11_win)?,
+        config::Fullscreen::Lower if covered => x11.lower(x11_win)?,
+        config::Fullscreen::Lower => x11.raise(x11_win)?,
+    }
+
+    Ok(())
 }
 
 async fn react_to_updates(
@@ -224,7 +508,10 @@ async fn react_to_updates(
     x11: Arc<X11Backend>,
     instruments: Rc<Mutex<Instruments>>,
     window: Rc<ApplicationWindow>,
+    x11_win: u32,
 ) -> anyhow::Result<()> {
+    // Restacking only follows changes, the bar starts out shown and on top
+    let mut covered = false;
     loop {
         tokio::select! {
             system_event = broadcast_receiver.recv() => {
@@ -238,24 +525,68 @@ async fn react_to_updates(
                         } = &mut *instruments.lock().expect("instruments mutex");
 
                         let _ = &bar.network.refresh(networks);
+                        if let Some(load) = &mut bar.load {
+                            load.refresh();
+                        }
                         let _ = &bar.bat.refresh(bat_manager);
                         let _ = &bar.storage.refresh(disks);
+                        if let Some(disk_io) = &mut bar.disk_io {
+                            disk_io.refresh();
+                        }
+                        // Brightness keys handled by the firmware don't trigger the file monitor
+                        if let Some(backlight) = &bar.backlight {
+                            backlight.refresh();
+                        }
+                        // dunst doesn't signal changes of the waiting count, nor its own start
+                        if let Some(notifications) = &bar.notifications {
+                            notifications.refresh();
+                        }
                     }
                     SystemEvent::Tick => {
                         let Instruments {
                             sys,
+                            components,
+                            pomodoro,
                             ..
                         } = &mut *instruments.lock().expect("instruments mutex");
 
                         let _ = &bar.clock.refresh();
-                        let _ = &bar.cpu.refresh(sys);
+                        let _ = &bar.cpu.refresh(sys, components);
                         let _ = &bar.mem.refresh(sys);
                         let _ = &bar.volume.refresh();
+                        let _ = &bar.timer.refresh(pomodoro);
+                        if let Some(mpris) = &mut bar.mpris {
+                            mpris.tick();
+                        }
+                    }
+                    SystemEvent::TimerUpdate => {
+                        let pomodoro = &mut instruments.lock().expect("instruments mutex").pomodoro;
+                        bar.timer.refresh(pomodoro);
+                    }
+                    SystemEvent::KeyboardUpdate => {
+                        if let Some(keyboard) = &bar.keyboard {
+                            keyboard.refresh();
+                        }
+                    }
+                    SystemEvent::MprisUpdate(players) => {
+                        if let Some(mpris) = &mut bar.mpris {
+                            mpris.update_players(players);
+                        }
+                    }
+                    SystemEvent::ModifiersUpdate(mods) => {
+                        if let Some(autohide) = &bar.autohide {
+                            autohide.lock().expect("autohide mutex").set_modifiers(mods);
+                        }
+                    }
+                    SystemEvent::Tray(event) => {
+                        if let Some(tray) = &mut bar.tray {
+                            tray.handle(event);
+                        }
                     }
                     SystemEvent::DesktopLayoutChange(desktop) => {
                         bar.win_count.label.set_text(&desktop.node_count_label());
                     }
-                    SystemEvent::DesktopStateUpdateNew(monitor) if monitor.monitor_name == bar.monitor_name => {
+                    SystemEvent::DesktopStateUpdateNew(monitor) if monitor.monitor_name == bar.monitor.name => {
                         let label = monitor.node_count_label();
                         bar.win_count.label.set_text(&label);
 
@@ -287,11 +618,11 @@ async fn react_to_updates(
                             }
                         }
 
-                        if let Some(desktop) = monitor.focused_desktop_state() {
-                            if desktop.is_active_node_fullscreen {
-                                window.set_visible(false)
-                            } else {
-                                window.set_visible(true)
+                        let is_covered = monitor.is_covered_by_fullscreen();
+                        if is_covered != covered {
+                            covered = is_covered;
+                            if let Err(err) = follow_fullscreen(&x11, x11_win, &window, &bar.cfg.bar.fullscreen, covered) {
+                                log::error!("Failed to restack the bar {:?}", err);
                             }
                         }
                     }
@@ -301,7 +632,89 @@ async fn react_to_updates(
             bar_event = channel_receiver.recv() => {
                 match bar_event? {
                     BarEvent::ClockClick => {
-                        let _ = &bar.clock.toggle_clock();
+                        if let Err(err) = bar.clock.toggle_clock(&x11, &bar.monitor, &bar.cfg) {
+                            log::error!("Failed to open calendar popup {:?}", err);
+                        }
+                        if let Some(autohide) = &bar.autohide {
+                            autohide.lock().expect("autohide mutex").set_pinned(bar.clock.popup.is_visible());
+                        }
+                    }
+                    BarEvent::CalendarClosed => {
+                        bar.clock.close();
+                        if let Some(autohide) = &bar.autohide {
+                            autohide.lock().expect("autohide mutex").set_pinned(false);
+                        }
+                    }
+                    BarEvent::CalendarChanged => {
+                        bar.clock.reload_events();
+                    }
+                    BarEvent::TimerToggle => {
+                        let pomodoro = &mut instruments.lock().expect("instruments mutex").pomodoro;
+                        pomodoro.apply(TimerAction::Toggle);
+                        bar.timer.refresh(pomodoro);
+                    }
+                    BarEvent::TimerReset => {
+                        let pomodoro = &mut instruments.lock().expect("instruments mutex").pomodoro;
+                        pomodoro.apply(TimerAction::Reset);
+                        bar.timer.refresh(pomodoro);
+                    }
+                    BarEvent::TimerAdjust(dy) => {
+                        let pomodoro = &mut instruments.lock().expect("instruments mutex").pomodoro;
+                        // Scrolling up makes the phase longer
+                        pomodoro.adjust(dy < 0.);
+                        bar.timer.refresh(pomodoro);
+                    }
+                    BarEvent::MprisPlayPause => {
+                        if let Some(mpris) = &bar.mpris {
+                            mpris.call("PlayPause");
+                        }
+                    }
+                    BarEvent::MprisScroll(dy) => {
+                        if let Some(mpris) = &bar.mpris {
+                            mpris.call(if dy > 0. { "Next" } else { "Previous" });
+                        }
+                    }
+                    BarEvent::SniItems(items) => {
+                        if let Some(sni) = &mut bar.sni {
+                            sni.show(items);
+                        }
+                    }
+                    BarEvent::NotificationsChanged => {
+                        if let Some(notifications) = &bar.notifications {
+                            notifications.refresh();
+                        }
+                    }
+                    BarEvent::NotificationsToggle => {
+                        if let Some(notifications) = &bar.notifications {
+                            notifications.toggle();
+                        }
+                    }
+                    BarEvent::KeyboardClick => {
+                        if let Some(keyboard) = &bar.keyboard {
+                            keyboard.next_group();
+                        }
+                    }
+                    BarEvent::BacklightChanged => {
+                        if let Some(backlight) = &bar.backlight {
+                            backlight.refresh();
+                        }
+                    }
+                    BarEvent::BacklightScroll(dy) => {
+                        if let Some(backlight) = &bar.backlight {
+                            // Scrolling up makes it brighter
+                            backlight.adjust(dy < 0.);
+                        }
+                    }
+                    BarEvent::ClockScroll(dy) => {
+                        let _ = &bar.clock.cycle_timezone(dy > 0.);
+                    }
+                    BarEvent::Close => {
+                        // Not children of the bar window, so they don't go away along with it
+                        bar.clock.popup.destroy();
+                        if let Some(tray) = &bar.tray {
+                            tray.release();
+                        }
+                        return Ok(());
                     }
                 }
             }
@@ -309,30 +722,58 @@ async fn react_to_updates(
     }
 }
 
+/// Exits with 1 if the bar couldn't be set up, e.g. another krowbar is running, so bspwmrc
+/// can tell.
 pub fn run(args: Args, cfg: KrowbarConfig) -> i32 {
-    let application = Application::builder().application_id("c.row.bar").build();
+    let application = Application::builder()
+        .application_id("c.row.bar")
+        // A unique app would hand the activation over to the running krowbar and exit, the
+        // `_KROWBAR_Sn` selection is what keeps krowbar to a single instance
+        .flags(gio::ApplicationFlags::NON_UNIQUE)
+        .build();
 
-    let css_cfg = cfg.clone();
-    application.connect_startup(move |_| match attach_css(css_cfg.clone()) {
-        Ok(_) => log::info!("css attached"),
-        Err(err) => log::error!("failed while attaching css {err}"),
-    });
+    let failed = Rc::new(Cell::new(false));
+    let configured = Cell::new(false);
+    let failed_cloned = failed.clone();
+    application.connect_activate(move |app| {
+        // The bars are set up once per process, however often GTK activates the app
+        if configured.replace(true) {
+            return;
+        }
 
-    application.connect_activate(
-        move |app| match app_configure(app, args.clone(), cfg.clone()) {
+        match app_configure(app, args.clone(), cfg.clone(), failed_cloned.clone()) {
             Ok(_) => log::info!("bar configured"),
-            Err(err) => log::error!("failed while conifguring app {err}"),
-        },
-    );
+            Err(err) => {
+                log::error!("failed while conifguring app {err}");
+                eprintln!("{err}");
+                failed_cloned.set(true);
+                app.quit();
+            }
+        }
+    });
 
     //XXX: have to pass empty array here, because default `.run` implicitly tries to parse args,
     //making it clash with clap.
-    application.run_with_args::<&str>(&[]).value()
+    let code = application.run_with_args::<&str>(&[]).value();
+    match failed.get() {
+        true => 1,
+        false => code,
+    }
 }
 
-fn app_configure(app: &Application, args: Args, cfg: KrowbarConfig) -> anyhow::Result<()> {
-    let x11 =
-        Arc::new(X11Backend::new().map_err(|op| anyhow!("Failed to init X11 backend {:?}", op))?);
+fn app_configure(
+    app: &Application,
+    args: Args,
+    cfg: KrowbarConfig,
+    failed: Rc<Cell<bool>>,
+) -> anyhow::Result<()> {
+    let watch_modifiers = cfg.autohide.enabled && cfg.autohide.modifier.is_some();
+    let with_xkb = enabled_widgets(&args).contains(&Widget::Keyboard) || watch_modifiers;
+    let x11 = Arc::new(
+        X11Backend::new(with_xkb).map_err(|op| anyhow!("Failed to init X11 backend {:?}", op))?,
+    );
+
+    x11.acquire_instance_lock(args.replace)?;
 
     let state = BspwmState::new().expect("pls");
 
@@ -341,44 +782,173 @@ fn app_configure(app: &Application, args: Args, cfg: KrowbarConfig) -> anyhow::R
     let sys = System::new_all();
     let disks = Disks::new_with_refreshed_list();
     let networks = Networks::new_with_refreshed_list();
+    let components = Components::new_with_refreshed_list();
     let bat_manager = battery::Manager::new().expect("Bat manager");
+    let pomodoro = Pomodoro::new(&cfg.timer);
+    let mpris = if enabled_widgets(&args).contains(&Widget::Mpris) {
+        MprisClient::new()
+            .inspect_err(|err| log::error!("Failed to connect to the session bus {err}"))
+            .ok()
+            .map(Rc::new)
+    } else {
+        None
+    };
 
     let instruments = Rc::new(Mutex::new(Instruments {
         sys,
         networks,
         disks,
+        components,
         bat_manager,
+        pomodoro,
+        mpris: mpris.clone(),
     }));
 
-    x11.monitors
-        .iter()
-        .try_for_each(|monitor| {
-            init_bar_window(
-                app,
-                monitor,
-                x11.clone(),
-                instruments.clone(),
-                receiver.clone(),
-                state.find_monitor(&monitor.name)?,
-                &args,
-                &cfg,
-            )
-        })?;
+    if enabled_widgets(&args).contains(&Widget::Sni) {
+        sni::start_watcher();
+    }
+
+    let bars = init_bars(app, &x11, &instruments, &sender, &state, &args, cfg)?;
+    // Keeps the channel open while the bars are set up anew, broadcasts wait for them meanwhile
+    let inactive_receiver = receiver.deactivate();
 
     let sender_cloned = sender.clone();
-    let _ = tokio::spawn(async move {
+    let bspwm_listener = tokio::spawn(async move {
         match listen_to_bspwm(sender_cloned, state).await {
             Ok(_) => log::info!("ok"),
             Err(err) => log::error!("failed while listening to bspwm {:?}", err),
         }
     });
 
+    if enabled_widgets(&args).contains(&Widget::Keyboard) && x11.xkb {
+        if let Err(err) = x11.watch_keyboard() {
+            log::error!("Failed to subscribe to keyboard changes {:?}", err);
+        }
+    }
+
+    if watch_modifiers && x11.xkb {
+        if let Err(err) = x11.watch_modifiers() {
+            log::error!("Failed to subscribe to modifier changes {:?}", err);
+        }
+    }
+
+    let (lifecycle_sender, lifecycle_receiver) = async_channel::bounded::<Lifecycle>(1);
+
+    let x11_events = x11.clone();
+    let sender_x11 = sender.clone();
+    let lifecycle_x11 = lifecycle_sender.clone();
+    tokio::task::spawn_blocking(move || {
+        let broadcast_blocking = |event: SystemEvent| {
+            let broadcast = sender_x11.broadcast(event);
+            if let Err(err) = tokio::runtime::Handle::current().block_on(broadcast) {
+                log::error!("Failed to broadcast the X11 event! {:?}", err)
+            }
+        };
+        let result = x11_events.listen_to_events(|event| match event {
+            X11Event::Tray(event) => broadcast_blocking(SystemEvent::Tray(event)),
+            X11Event::KeyboardChange => broadcast_blocking(SystemEvent::KeyboardUpdate),
+            X11Event::ModifiersChange(mods) => {
+                broadcast_blocking(SystemEvent::ModifiersUpdate(mods))
+            }
+            X11Event::Replaced => {
+                // Fails only once the bar is shutting down anyway
+                let _ = lifecycle_x11.send_blocking(Lifecycle::Quit);
+            }
+        });
+
+        match result {
+            Ok(_) => log::info!("ok"),
+            Err(err) => log::error!("failed while listening to X11 events {:?}", err),
+        }
+    });
+
+    let (ipc_sender, ipc_receiver) = async_channel::bounded::<IpcCommand>(8);
+    let ipc_listener = tokio::spawn(async move {
+        match ipc::listen(ipc_sender).await {
+            Ok(_) => log::info!("ok"),
+            Err(err) => log::error!("failed while listening for IPC commands {:?}", err),
+        }
+    });
+
+    // Applied here, once, instead of in every bar, as the timer state is shared
+    let ipc_instruments = instruments.clone();
+    let sender_ipc = sender.clone();
+    gtk::glib::spawn_future_local(async move {
+        while let Ok(command) = ipc_receiver.recv().await {
+            match command {
+                IpcCommand::Timer(action) => {
+                    ipc_instruments
+                        .lock()
+                        .expect("instruments mutex")
+                        .pomodoro
+                        .apply(action);
+
+                    if let Err(err) = sender_ipc.broadcast(SystemEvent::TimerUpdate).await {
+                        log::error!("Failed to broadcast the timer update! {:?}", err)
+                    }
+                }
+            }
+        }
+    });
+
+    // Queried here, once, instead of in every bar, as the players are the same on every monitor
+    if let Some(client) = mpris {
+        listen_to_mpris(client, sender.clone());
+    }
+
+    tokio::spawn(async move {
+        match listen_to_signals(lifecycle_sender).await {
+            Ok(_) => log::info!("ok"),
+            Err(err) => log::error!("failed while listening to signals {:?}", err),
+        }
+    });
+
+    let app_cloned = app.clone();
+    let x11_reload = x11.clone();
+    let sender_reload = sender.clone();
+    gtk::glib::spawn_future_local(async move {
+        let _inactive_receiver = inactive_receiver;
+        let mut bars = Some(bars);
+        while let Ok(Lifecycle::Reload) = lifecycle_receiver.recv().await {
+            log::info!("reloading the config and styles");
+            // Keeps the app running while it has no windows
+            let _hold = app_cloned.hold();
+            if let Some(bars) = bars.take() {
+                bars.close().await;
+            }
+
+            let reloaded = reload(
+                &app_cloned,
+                &x11_reload,
+                &instruments,
+                &sender_reload,
+                &args,
+            );
+            match reloaded {
+                Ok(reloaded) => bars = Some(reloaded),
+                Err(err) => {
+                    log::error!("Failed to reload, quitting {:?}", err);
+                    failed.set(true);
+                    break;
+                }
+            }
+        }
+        log::info!("shutting down");
+
+        bspwm_listener.abort();
+        ipc_listener.abort();
+        ipc::remove_socket();
+        if let Some(bars) = bars.take() {
+            bars.close().await;
+        }
+        app_cloned.quit();
+    });
+
     let sender_tick = sender.clone();
     let _ = tokio::spawn(async move {
-        // Might want to reduce the sleep, to get faster clock updates.
-        let mut interval = tokio::time::interval(Duration::from_secs(1));
         loop {
-            interval.tick().await;
+            // Sleep until the next full second, so the clock flips together with the wall clock.
+            tokio::time::sleep(until_next_second()).await;
             let result = sender_tick.broadcast(SystemEvent::Tick).await;
             match result {
                 Ok(_) => {}
@@ -403,10 +973,137 @@ fn app_configure(app: &Application, args: Args, cfg: KrowbarConfig) -> anyhow::R
     Ok(())
 }
 
-fn attach_css(cfg: KrowbarConfig) -> anyhow::Result<()> {
-    let provider = gtk::CssProvider::new();
+/// Lists the tray items off the bar's event loop, a slow item must not hold up the rest of
+/// the bar.
+fn listen_to_sni(client: Rc<SniClient>, sender: async_channel::Sender<BarEvent>) {
+    let (changed, refresh) = async_channel::bounded::<()>(1);
+    // Picks up the items registered before us
+    let _ = changed.try_send(());
+    client.watch(move || {
+        // Items emit lots of signals, a refresh already queued covers this one too
+        let _ = changed.try_send(());
+    });
+
+    gtk::glib::spawn_future_local(async move {
+        while refresh.recv().await.is_ok() {
+            let items = client.items().await.unwrap_or_else(|err| {
+                log::error!("Failed to list tray items {err}");
+                vec![]
+            });
+            if sender.send(BarEvent::SniItems(items)).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+fn listen_to_mpris(client: Rc<MprisClient>, sender: async_broadcast::Sender<SystemEvent>) {
+    let (changed, refresh) = async_channel::bounded::<()>(1);
+    // Picks up the players started before us
+    let _ = changed.try_send(());
+    client.watch(move || {
+        // Players emit lots of property changes, a refresh already queued covers this one too
+        let _ = changed.try_send(());
+    });
+
+    gtk::glib::spawn_future_local(async move {
+        while refresh.recv().await.is_ok() {
+            let players = client.players().await.unwrap_or_else(|err| {
+                log::error!("Failed to list MPRIS players {err}");
+                vec![]
+            });
+            if let Err(err) = sender.broadcast(SystemEvent::MprisUpdate(players)).await {
+                log::error!("Failed to broadcast the MPRIS players! {:?}", err)
+            }
+        }
+    });
+}
+
+#[derive(Debug, PartialEq)]
+enum Lifecycle {
+    Quit,
+    Reload,
+}
 
+/// SIGTERM and SIGINT quit, SIGHUP reloads the config and styles.
+async fn listen_to_signals(sender: async_channel::Sender<Lifecycle>) -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+            _ = hangup.recv() => sender.send(Lifecycle::Reload).await?,
+        }
+    }
+    sender.send(Lifecycle::Quit).await?;
+
+    // Don't make the user reach for SIGKILL, if the clean shutdown gets stuck
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
+    };
+    log::error!("Forced to exit");
+    std::process::exit(1);
+}
+
+fn until_next_second() -> Duration {
+    let subsec = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.subsec_nanos())
+        .unwrap_or(0);
+
+    // A few extra millis make sure we wake up past the boundary, not right before it
+    Duration::from_nanos(1_000_000_000 - subsec as u64) + Duration::from_millis(5)
+}
+
+/// Compiles the built-in and user styles. On failure the error is logged, noted in `cfg.errors`
+/// for the bar to show, and the default theme without user styles is used instead.
+fn compile_styles(cfg: &mut KrowbarConfig, composited: bool) -> String {
+    let style_path = config::style_path();
+    let err = match compile_css(cfg, composited, Some(&style_path)) {
+        Ok(css) => return css,
+        Err(err) => err,
+    };
+
+    log::error!("failed while compiling styles, falling back to the default theme\n{err}");
+    // grass puts the message first and the file:line:col last, with the snippet in between
+    let message = err.to_string();
+    let mut lines = message.lines().map(str::trim);
+    let summary = lines
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches("Error: ");
+    let error = match lines.next_back() {
+        Some(location) => format!("{summary} ({})", location.trim_start_matches("./")),
+        None => summary.to_string(),
+    };
+    cfg.errors.push(error);
+
+    cfg.theme = config::Theme::default();
+    cfg.font = config::Font::default();
+    compile_css(cfg, composited, None).expect("Built-in styles have to compile")
+}
+
+#[derive(Serialize)]
+struct BarStyle {
+    composited: bool,
+    border_width: u16,
+    border_radius: u16,
+}
+
+fn compile_css(
+    cfg: &KrowbarConfig,
+    composited: bool,
+    style_path: Option<&std::path::Path>,
+) -> anyhow::Result<String> {
     let mut tt = TinyTemplate::new();
+    tt.add_template(
+        "bar-template",
+        include_str!("../resources/bar-template.scss.tmpl"),
+    )?;
     tt.add_template(
         "theme-template",
         include_str!("../resources/theme-template.scss.tmpl"),
@@ -415,18 +1112,37 @@ fn attach_css(cfg: KrowbarConfig) -> anyhow::Result<()> {
         "font-template",
         include_str!("../resources/font-template.scss.tmpl"),
     )?;
+    let bar_style = BarStyle {
+        composited,
+        border_width: cfg.bar.border_width,
+        border_radius: cfg.bar.border_radius,
+    };
+    let bar_css = tt.render("bar-template", &bar_style)?;
     let theme_css = tt.render("theme-template", &cfg.theme)?;
     let font_css = tt.render("font-template", &cfg.font)?;
 
     let mut sass = String::new();
 
     let base = include_str!("../resources/base.scss");
+    sass.push_str(&bar_css);
     sass.push_str(&font_css);
     sass.push_str(&theme_css);
     sass.push_str(base);
 
-    let css = grass::from_string(sass, &grass::Options::default()).expect("Valid css expected");
-    provider.load_from_data(&css);
+    if let Some(style_path) = style_path.filter(|path| path.exists()) {
+        // Imported rather than inlined, so errors point at the user's file
+        sass.push_str(&format!("\n@import {:?};\n", style_path));
+        log::info!("loading user styles from {:?}", style_path);
+    }
+
+    // Lets user styles @import their own partials
+    let options = grass::Options::default().load_path(xdg::config_dir());
+    grass::from_string(sass, &options).map_err(|err| anyhow!("{err}"))
+}
+
+fn attach_css(css: &str) -> anyhow::Result<gtk::CssProvider> {
+    let provider = gtk::CssProvider::new();
+    provider.load_from_data(css);
 
     gtk::style_context_add_provider_for_display(
         &gdk::Display::default().ok_or(anyhow!("Failed to get default display"))?,
@@ -434,7 +1150,171 @@ fn attach_css(cfg: KrowbarConfig) -> anyhow::Result<()> {
         gtk::STYLE_PROVIDER_PRIORITY_APPLICATION,
     );
 
-    Ok(())
+    Ok(provider)
+}
+
+/// bspwm padding set for a bar, along with the value it replaced, so it can be restored on exit.
+/// Padding is per monitor, so padding set for other monitors in bspwmrc stays intact.
+struct Padding {
+    monitor: String,
+    setting: &'static str,
+    previous: Option<String>,
+}
+
+impl Padding {
+    fn set(monitor: &Monitor, position: &Position, size: u16) -> anyhow::Result<Padding> {
+        let setting = match position {
+            Position::Top => "top_padding",
+            Position::Bottom => "bottom_padding",
+        };
+
+        let previous = std::process::Command::new("bspc")
+            .arg("config")
+            .arg("-m")
+            .arg(&monitor.name)
+            .arg(setting)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+
+        std::process::Command::new("bspc")
+            .arg("config")
+            .arg("-m")
+            .arg(&monitor.name)
+            .arg(setting)
+            .arg(size.to_string())
+            .output()
+            .map_err(|op| anyhow!("Failed while setting {setting} {}", op))?;
+
+        Ok(Padding {
+            monitor: monitor.name.clone(),
+            setting,
+            previous,
+        })
+    }
+
+    fn restore(&self) {
+        let Some(previous) = &self.previous else {
+            return;
+        };
+
+        let result = std::process::Command::new("bspc")
+            .arg("config")
+            .arg("-m")
+            .arg(&self.monitor)
+            .arg(self.setting)
+            .arg(previous)
+            .output();
+        if let Err(err) = result {
+            log::error!("Failed to restore {} {:?}", self.setting, err);
+        }
+    }
+}
+
+/// The bars on every monitor, along with the styles they were created with.
+struct Bars {
+    windows: Vec<BarWindow>,
+    css: gtk::CssProvider,
+}
+
+impl Bars {
+    async fn close(self) {
+        for window in self.windows {
+            window.close().await;
+        }
+        if let Some(display) = gdk::Display::default() {
+            gtk::style_context_remove_provider_for_display(&display, &self.css);
+        }
+    }
+}
+
+struct BarWindow {
+    window: Rc<ApplicationWindow>,
+    events: async_channel::Sender<BarEvent>,
+    updates: gtk::glib::JoinHandle<()>,
+    padding: Option<Padding>,
+}
+
+impl BarWindow {
+    async fn close(self) {
+        // The bar lets go of what lives outside of GTK first, e.g. the tray selection
+        if self.events.send(BarEvent::Close).await.is_ok() {
+            let _ = self.updates.await;
+        }
+        self.window.destroy();
+        if let Some(padding) = &self.padding {
+            padding.restore();
+        }
+    }
+}
+
+/// Reads the config and styles again and sets up the bars anew. The X11 connection, the
+/// listeners and the instruments are kept, so the timer keeps running.
+fn reload(
+    app: &Application,
+    x11: &Arc<X11Backend>,
+    instruments: &Rc<Mutex<Instruments>>,
+    sender: &async_broadcast::Sender<SystemEvent>,
+    args: &Args,
+) -> anyhow::Result<Bars> {
+    let cfg = config::read(args)?;
+    instruments
+        .lock()
+        .expect("instruments mutex")
+        .pomodoro
+        .reconfigure(&cfg.timer);
+
+    let watch_modifiers = cfg.autohide.enabled && cfg.autohide.modifier.is_some();
+    if watch_modifiers && !x11.xkb {
+        log::warn!("XKB wasn't set up on start, restart krowbar for the autohide modifier");
+    } else if watch_modifiers {
+        x11.watch_modifiers()
+            .map_err(|op| anyhow!("Failed to subscribe to modifier changes {:?}", op))?;
+    }
+
+    let state = BspwmState::new()?;
+    init_bars(app, x11, instruments, sender, &state, args, cfg)
+}
+
+fn init_bars(
+    app: &Application,
+    x11: &Arc<X11Backend>,
+    instruments: &Rc<Mutex<Instruments>>,
+    sender: &async_broadcast::Sender<SystemEvent>,
+    state: &BspwmState,
+    args: &Args,
+    mut cfg: KrowbarConfig,
+) -> anyhow::Result<Bars> {
+    let composited = x11.is_composited().unwrap_or(false);
+    log::info!("compositor running: {composited}");
+    // GDK gives its windows the 32-bit visual when the screen has one, translucency needs both
+    let rgba = gdk::Display::default().is_some_and(|display| display.is_rgba());
+    if composited && !rgba {
+        let err = "no 32-bit visual, translucent colors are made opaque".to_string();
+        log::error!("{err}");
+        cfg.errors.push(err);
+    }
+    let composited = composited && rgba;
+    let css = attach_css(&compile_styles(&mut cfg, composited))?;
+    log::info!("css attached");
+
+    let mut windows = vec![];
+    for monitor in x11.monitors.iter() {
+        let window = init_bar_window(
+            app,
+            monitor,
+            x11.clone(),
+            instruments.clone(),
+            sender.new_receiver(),
+            state.find_monitor(&monitor.name)?,
+            args,
+            &cfg,
+        )?;
+        windows.push(window);
+    }
+
+    Ok(Bars { windows, css })
 }
 
 fn init_bar_window(
@@ -446,32 +1326,70 @@ fn init_bar_window(
     monitor_state: &MonitorState,
     args: &Args,
     cfg: &KrowbarConfig,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<BarWindow> {
     let window = ApplicationWindow::builder()
         .application(app)
         .focusable(false)
         .can_focus(false)
         .title("krowbar")
         .css_name("krowbar")
+        // Keeps the widgets within the rounded corners
+        .overflow(gtk::Overflow::Hidden)
         .build();
 
-    let (sender, receiver_bar_event) = async_channel::bounded::<BarEvent>(1);
+    let (sender, receiver_bar_event) = async_channel::bounded::<BarEvent>(8);
 
-    let bar = Bar::new(
+    let mut bar = Bar::new(
         monitor_state,
         x11.clone(),
         instruments.clone(),
         sender.clone(),
         monitor,
         args,
+        cfg,
     );
 
     window.set_child(Some(&bar.bar_box));
 
-    let x11_cloned = x11.clone();
     let window_ref = Rc::new(window);
+    window_ref.set_visible(true);
+
+    let x11_win = window_ref
+        .surface()
+        .ok_or(anyhow!("No surface on window!"))?
+        .downcast::<gdk4_x11::X11Surface>()
+        .map_err(|_| anyhow!("Failed to cast GTK surface to X11 surface"))?
+        .xid() as u32; //check if we can safely cast here
+
+    if enabled_widgets(args).contains(&Widget::Tray) && is_tray_monitor(&x11, monitor, cfg) {
+        match Tray::new(x11.clone(), x11_win, monitor, cfg, &bar.bar_box) {
+            Ok(tray) => bar.tray = Some(tray),
+            Err(err) => log::error!("Failed to start the system tray {:?}", err),
+        }
+    }
+
+    if cfg.autohide.applies_to(&monitor.name) {
+        let autohide = Autohide::new(x11.clone(), x11_win, monitor, cfg);
+        let autohide = Rc::new(Mutex::new(autohide));
+
+        // Told directly rather than through the bounded channel, a dropped leave would keep
+        // the bar revealed for good
+        let hover = gtk::EventControllerMotion::new();
+        let entered = autohide.clone();
+        hover.connect_enter(move |_, _, _| {
+            entered.lock().expect("autohide mutex").set_hovered(true);
+        });
+        let left = autohide.clone();
+        hover.connect_leave(move |_| {
+            left.lock().expect("autohide mutex").set_hovered(false);
+        });
+        window_ref.add_controller(hover);
+        bar.autohide = Some(autohide);
+    }
+
+    let x11_cloned = x11.clone();
     let window_ref_cloned = window_ref.clone();
-    gtk::glib::spawn_future_local(async move {
+    let updates = gtk::glib::spawn_future_local(async move {
         let result = react_to_updates(
             receiver,
             receiver_bar_event,
@@ -479,6 +1397,7 @@ fn init_bar_window(
             x11_cloned,
             instruments.clone(),
             window_ref_cloned,
+            x11_win,
         )
         .await;
 
@@ -488,15 +1407,6 @@ fn init_bar_window(
         }
     });
 
-    window_ref.set_visible(true);
-
-    let x11_win = window_ref
-        .surface()
-        .ok_or(anyhow!("No surface on window!"))?
-        .downcast::<gdk4_x11::X11Surface>()
-        .map_err(|_| anyhow!("Failed to cast GTK surface to X11 surface"))?
-        .xid() as u32; //check if we can safely cast here
-
     // This sucks, but I don't know how to prevent GTK created window being focused by BSPWM.
     std::process::Command::new("bspc")
         .arg("config")
@@ -507,30 +1417,40 @@ fn init_bar_window(
         .output()
         .map_err(|op| anyhow!("Failed while setting border_width {}", op))?;
 
-    if !args.no_pad {
-        match cfg.bar.position {
-            Position::Top => {
-                std::process::Command::new("bspc")
-                    .arg("config")
-                    .arg("top_padding")
-                    .arg(cfg.bar.height.to_string())
-                    .output()
-                    .map_err(|op| anyhow!("Failed while setting top padding {}", op))?;
-            }
-            Position::Bottom => {
-                std::process::Command::new("bspc")
-                    .arg("config")
-                    .arg("bottom_padding")
-                    .arg(cfg.bar.height.to_string())
-                    .output()
-                    .map_err(|op| anyhow!("Failed while setting bottom padding {}", op))?;
-            }
-        }
-    }
-
     x11.clone()
         .setup(x11_win, monitor, cfg.clone())
         .map_err(|op| anyhow!("Failed to setup window via X11 {:?}", op))?;
 
-    Ok(())
+    Ok(BarWindow {
+        window: window_ref,
+        events: sender,
+        updates,
+        padding: reserve_space(&x11, x11_win, monitor, args, cfg)?,
+    })
+}
+
+fn reserve_space(
+    x11: &X11Backend,
+    x11_win: u32,
+    monitor: &Monitor,
+    args: &Args,
+    cfg: &KrowbarConfig,
+) -> anyhow::Result<Option<Padding>> {
+    if args.no_pad {
+        return Ok(None);
+    }
+
+    // Windows go under an autohiding bar, only its trigger strip is kept clear
+    let size = match cfg.autohide.applies_to(&monitor.name) {
+        true => cfg.autohide.trigger_size,
+        false => monitor.bar_reserved_space(&cfg.bar),
+    };
+    match cfg.bar.reserve {
+        Reserve::Strut => {
+            x11.set_strut(x11_win, monitor, &cfg.bar.position, size)
+                .map_err(|op| anyhow!("Failed to set strut {:?}", op))?;
+            Ok(None)
+        }
+        Reserve::Padding => Ok(Some(Padding::set(monitor, &cfg.bar.position, size)?)),
+    }
 }
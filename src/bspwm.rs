@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use anyhow::anyhow;
 use bspc_rs::events::{
     self, DesktopEvent, Event, NodeEvent, NodeFlagInfo, NodeStateInfo, Subscription,
@@ -36,7 +38,8 @@ pub struct DesktopState {
     pub node_count: usize,
     pub is_active: bool,
     pub is_urgent: bool,
-    pub is_active_node_fullscreen: bool,
+    /// Fullscreen nodes cover the whole monitor, focused or not.
+    pub fullscreen_nodes: HashSet<u32>,
     pub active_node: Option<u32>,
 }
 
@@ -77,6 +80,22 @@ impl BspwmState {
             ))
     }
 
+    pub fn find_desktop_mut(&mut self, desktop_id: u32) -> Option<&mut DesktopState> {
+        self.monitors
+            .iter_mut()
+            .find_map(|monitor| monitor.find_desktop_mut(desktop_id))
+    }
+
+    /// Re-reads the fullscreen nodes of desktops whose subtrees were swapped or transferred,
+    /// a moved subtree may carry any number of fullscreen nodes along.
+    pub fn update_fullscreen_nodes(&mut self, desktop_ids: &[u32]) {
+        for &desktop_id in desktop_ids {
+            if let Some(desktop) = self.find_desktop_mut(desktop_id) {
+                desktop.update_fullscreen_nodes();
+            }
+        }
+    }
+
     pub fn update_all_desktop_window_count(&mut self) {
         for monitor in self.monitors.iter_mut() {
             for desktop in monitor.desktops.iter_mut() {
@@ -110,10 +129,26 @@ impl MonitorState {
         let stdout = String::from_utf8(output.stdout)?;
         let desktop_names = stdout.split_whitespace().map(String::from);
 
+        // Names may repeat across monitors, so desktops are told apart by their IDs
+        let desktop_ids = query::query_desktops(
+            false,
+            None,
+            Some(MonitorSelector(&monitor_name)),
+            None,
+            None,
+        )?;
+        if desktop_ids.len() != desktop_names.clone().count() {
+            return Err(anyhow!("Desktops of {monitor_name} changed while querying"));
+        }
+
         let active_desktop = Self::find_active_desktop(&monitor_name);
-        let desktops: Vec<DesktopState> = desktop_names
-            .map(|desktop_name| DesktopState::new(desktop_name, active_desktop))
-            .collect::<anyhow::Result<Vec<DesktopState>>>()?;
+        let desktops: Vec<DesktopState> = desktop_ids
+            .into_iter()
+            .zip(desktop_names)
+            .map(|(desktop_id, desktop_name)| {
+                DesktopState::new(desktop_id, desktop_name, active_desktop)
+            })
+            .collect();
 
         Ok(MonitorState {
             monitor_id,
@@ -152,26 +187,12 @@ impl MonitorState {
         for desktop in self.desktops.iter_mut() {
             if desktop.desktop_id == desktop_id {
                 desktop.active_node = Some(active_node_id);
-                desktop.is_active_node_fullscreen =
-                    Self::is_node_focused(active_node_id, desktop_id).unwrap_or(false)
             } else {
                 desktop.active_node = None;
-                desktop.is_active_node_fullscreen = false;
             }
         }
     }
 
-    pub fn is_node_focused(node_id: u32, desktop_id: u32) -> anyhow::Result<bool> {
-        let result = query::query_nodes(
-            Some(NodeSelector(&node_id.to_string())),
-            None,
-            Some(DesktopSelector(&desktop_id.to_string())),
-            Some(NodeSelector(".fullscreen")),
-        )?;
-
-        Ok(!result.is_empty())
-    }
-
     pub fn find_desktop(&self, desktop_id: u32) -> Option<&DesktopState> {
         self.desktops
             .iter()
@@ -195,6 +216,12 @@ impl MonitorState {
         self.desktops.iter().find(|desktop| desktop.is_active)
     }
 
+    /// Whether the desktop shown on the monitor has a fullscreen node covering the bar.
+    pub fn is_covered_by_fullscreen(&self) -> bool {
+        self.focused_desktop_state()
+            .is_some_and(|desktop| !desktop.fullscreen_nodes.is_empty())
+    }
+
     pub fn node_count_label(&self) -> String {
         self.focused_desktop_state()
             .map(|desktop| desktop.node_count_label())
@@ -204,45 +231,72 @@ impl MonitorState {
 
 impl DesktopState {
     pub fn new(
+        desktop_id: DesktopId,
         desktop_name: String,
         active_desktop_id: Option<DesktopId>,
-    ) -> anyhow::Result<DesktopState> {
-        let desktop_id = query::query_desktops(
-            false,
-            None,
-            None,
-            Some(DesktopSelector(&desktop_name)),
-            None,
-        )?
-        .first()
-        .copied()
-        .ok_or(anyhow!("No desktop ID found"))?;
-
-        let node_count = Self::count_nodes(&desktop_name);
+    ) -> DesktopState {
+        let node_count = Self::count_nodes(desktop_id);
 
         let active_node: Option<u32> = query::query_nodes(
             None,
             None,
-            Some(DesktopSelector(&desktop_name)),
+            Some(DesktopSelector(&format!("{desktop_id:#x}"))),
             Some(NodeSelector(".active")),
         )
         .ok()
         .and_then(|nodes| nodes.first().copied());
 
-        Ok(DesktopState {
+        DesktopState {
             desktop_id,
             desktop_name,
             layout: Layout::Tiled, // XXX: is this safe to assume?
             node_count,
             is_active: Some(desktop_id) == active_desktop_id,
             is_urgent: false,
-            is_active_node_fullscreen: false,
+            fullscreen_nodes: Self::query_fullscreen_nodes(desktop_id),
             active_node,
-        })
+        }
     }
 
-    pub fn set_fullscreen(&mut self, is_fullscreen: bool) {
-        self.is_active_node_fullscreen = is_fullscreen
+    pub fn set_fullscreen(&mut self, node_id: u32, is_fullscreen: bool) {
+        if is_fullscreen {
+            self.fullscreen_nodes.insert(node_id);
+        } else {
+            self.fullscreen_nodes.remove(&node_id);
+        }
+    }
+
+    /// Picks up a new node that a rule made fullscreen, bspwm sends no state event for those.
+    pub fn add_node(&mut self, node_id: u32) {
+        let is_fullscreen = query::query_nodes(
+            None,
+            None,
+            None,
+            Some(NodeSelector(&format!("{node_id:#x}.fullscreen"))),
+        )
+        .is_ok_and(|nodes| nodes.contains(&node_id));
+
+        self.set_fullscreen(node_id, is_fullscreen);
+    }
+
+    /// Forgets a node which left the desktop, returns whether it was fullscreen.
+    pub fn remove_node(&mut self, node_id: u32) -> bool {
+        self.fullscreen_nodes.remove(&node_id)
+    }
+
+    fn update_fullscreen_nodes(&mut self) {
+        self.fullscreen_nodes = Self::query_fullscreen_nodes(self.desktop_id);
+    }
+
+    fn query_fullscreen_nodes(desktop_id: DesktopId) -> HashSet<u32> {
+        query::query_nodes(
+            None,
+            None,
+            Some(DesktopSelector(&format!("{desktop_id:#x}"))),
+            Some(NodeSelector(".fullscreen")),
+        )
+        .map(|nodes| nodes.into_iter().collect())
+        .unwrap_or_default()
     }
 
     pub fn set_urgent(&mut self, is_urgent: bool) {
@@ -250,14 +304,14 @@ impl DesktopState {
     }
 
     fn update_node_count(&mut self) {
-        self.node_count = Self::count_nodes(&self.desktop_name);
+        self.node_count = Self::count_nodes(self.desktop_id);
     }
 
-    fn count_nodes(desktop_name: &str) -> usize {
+    fn count_nodes(desktop_id: DesktopId) -> usize {
         query::query_nodes(
             None,
             None,
-            Some(DesktopSelector(desktop_name)),
+            Some(DesktopSelector(&format!("{desktop_id:#x}"))),
             Some(NodeSelector(".window.!hidden")),
         )
         .map(|nodes| nodes.len())
@@ -347,16 +401,16 @@ pub async fn listen_to_bspwm(
             Event::NodeEvent(NodeEvent::NodeState(NodeStateInfo {
                 monitor_id,
                 desktop_id,
+                node_id,
                 state: State::Fullscreen,
                 switch,
-                ..
             })) => {
                 let updated_monitor = state.find_monitor_by_id(monitor_id)?;
                 let updated_desktop = updated_monitor.find_desktop_mut(desktop_id);
                 if let Some(desktop) = updated_desktop {
                     match switch {
-                        Switch::On => desktop.set_fullscreen(true),
-                        Switch::Off => desktop.set_fullscreen(false),
+                        Switch::On => desktop.set_fullscreen(node_id, true),
+                        Switch::Off => desktop.set_fullscreen(node_id, false),
                     }
                 }
 
@@ -375,6 +429,9 @@ pub async fn listen_to_bspwm(
             }
             Event::NodeEvent(NodeEvent::NodeAdd(node_add_info)) => {
                 state.update_all_desktop_window_count();
+                if let Some(desktop) = state.find_desktop_mut(node_add_info.desktop_id) {
+                    desktop.add_node(node_add_info.node_id);
+                }
                 let updated_monitor = state.find_monitor_by_id(node_add_info.monitor_id)?;
 
                 let _ = sender
@@ -383,6 +440,9 @@ pub async fn listen_to_bspwm(
             }
             Event::NodeEvent(NodeEvent::NodeRemove(node_remove_info)) => {
                 state.update_all_desktop_window_count();
+                if let Some(desktop) = state.find_desktop_mut(node_remove_info.desktop_id) {
+                    desktop.remove_node(node_remove_info.node_id);
+                }
                 let updated_monitor = state.find_monitor_by_id(node_remove_info.monitor_id)?;
 
                 let _ = sender
@@ -391,6 +451,10 @@ pub async fn listen_to_bspwm(
             }
             Event::NodeEvent(NodeEvent::NodeSwap(node_swap_info)) => {
                 state.update_all_desktop_window_count();
+                state.update_fullscreen_nodes(&[
+                    node_swap_info.src_desktop_id,
+                    node_swap_info.dst_desktop_id,
+                ]);
                 let updated_monitor = state.find_monitor_by_id(node_swap_info.dst_monitor_id)?;
 
                 let _ = sender
@@ -405,6 +469,10 @@ pub async fn listen_to_bspwm(
             }
             Event::NodeEvent(NodeEvent::NodeTransfer(node_transfer_info)) => {
                 state.update_all_desktop_window_count();
+                state.update_fullscreen_nodes(&[
+                    node_transfer_info.src_desktop_id,
+                    node_transfer_info.dst_desktop_id,
+                ]);
                 let updated_monitor =
                     state.find_monitor_by_id(node_transfer_info.dst_monitor_id)?;
 
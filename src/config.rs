@@ -1,18 +1,46 @@
-use serde::{Deserialize, Serialize};
-use std::fs;
+use anyhow::anyhow;
+use serde::{
+    de::{Error, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-use crate::Args;
+use crate::{xdg, Args};
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct KrowbarConfig {
+    /// Configs layered under this one, relative to it, e.g. a shared `colors.toml`.
+    #[serde(skip_serializing)]
+    pub include: Vec<String>,
+    #[serde(deserialize_with = "deserialize_theme")]
     pub theme: Theme,
     pub font: Font,
     pub bar: Bar,
+    pub cpu: Cpu,
+    pub load: Load,
+    pub mem: Mem,
+    pub storage: Storage,
+    pub disk_io: DiskIo,
+    pub clock: Clock,
+    pub timer: Timer,
+    pub mpris: Mpris,
+    pub tray: Tray,
+    pub keyboard: Keyboard,
+    pub backlight: Backlight,
+    pub autohide: Autohide,
+    /// Problems found while loading the config or the styles, shown on the bar.
+    #[serde(skip)]
+    pub errors: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct Theme {
     pub fg: String,
     pub fg_dim: String,
@@ -28,6 +56,7 @@ pub struct Theme {
     pub bright: String,
     pub bright_dim: String,
     pub accent: String,
+    pub border: String,
 }
 
 impl Default for Theme {
@@ -47,12 +76,69 @@ impl Default for Theme {
             bright: "#cacaca".to_string(),
             bright_dim: "#828282".to_string(),
             accent: "#bc5653".to_string(),
+            border: "#232323".to_string(),
         }
     }
 }
 
+pub const THEME_NAMES: [&str; 3] = ["classic", "mute", "moss"];
+
+impl Theme {
+    /// Themes shipped with krowbar, classic being the default one.
+    pub fn named(name: &str) -> Option<Theme> {
+        let contents = match name {
+            "classic" => return Some(Theme::default()),
+            "mute" => include_str!("../resources/themes/mute.toml"),
+            "moss" => include_str!("../resources/themes/moss.toml"),
+            _ => return None,
+        };
+
+        Some(toml::from_str(contents).expect("Valid built-in theme"))
+    }
+}
+
+/// Accepts either a theme name, e.g. `theme = "moss"`, or a table of colors. The table may
+/// set a `name` too, to tweak one of the named themes.
+fn deserialize_theme<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Theme, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ThemeSpec {
+        Name(String),
+        Table(toml::Table),
+    }
+
+    let (name, overrides) = match ThemeSpec::deserialize(deserializer)? {
+        ThemeSpec::Name(name) => (Some(name), toml::Table::new()),
+        ThemeSpec::Table(mut table) => match table.remove("name") {
+            Some(toml::Value::String(name)) => (Some(name), table),
+            Some(_) => return Err(D::Error::custom("theme name has to be a string")),
+            None => (None, table),
+        },
+    };
+
+    let base = match name {
+        Some(name) => Theme::named(&name).ok_or_else(|| {
+            D::Error::custom(format!(
+                "unknown theme {name:?}, expected one of {}",
+                THEME_NAMES.join(", ")
+            ))
+        })?,
+        None => Theme::default(),
+    };
+
+    let mut theme = match toml::Value::try_from(base).map_err(D::Error::custom)? {
+        toml::Value::Table(theme) => theme,
+        _ => unreachable!("Theme serializes into a table"),
+    };
+    theme.extend(overrides);
+
+    toml::Value::Table(theme)
+        .try_into()
+        .map_err(D::Error::custom)
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct Font {
     pub font_family: String,
     pub font_size: String,
@@ -70,10 +156,19 @@ impl Default for Font {
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct Bar {
     pub height: u16,
     pub position: Position,
+    pub width: Length,
+    /// Gap between the monitor edges and the sides of the bar.
+    pub margin_x: Length,
+    /// Gap between the monitor edge and the bar.
+    pub margin_y: Length,
+    pub border_width: u16,
+    pub border_radius: u16,
+    pub reserve: Reserve,
+    pub fullscreen: Fullscreen,
 }
 
 impl Default for Bar {
@@ -81,6 +176,76 @@ impl Default for Bar {
         Bar {
             height: 30,
             position: Position::Top,
+            width: Length::Percent(100.0),
+            margin_x: Length::Pixels(0),
+            margin_y: Length::Pixels(0),
+            border_width: 0,
+            border_radius: 0,
+            reserve: Reserve::Strut,
+            fullscreen: Fullscreen::Hide,
+        }
+    }
+}
+
+/// Pixels, e.g. `20`, or a percentage of the monitor size, e.g. `"80%"`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "LengthSpec", into = "LengthSpec")]
+pub enum Length {
+    Pixels(u16),
+    Percent(f32),
+}
+
+impl Length {
+    pub fn resolve(&self, total: u16) -> u16 {
+        match self {
+            Length::Pixels(pixels) => (*pixels).min(total),
+            Length::Percent(percent) => (total as f32 * percent / 100.0).round() as u16,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum LengthSpec {
+    Pixels(u16),
+    Text(String),
+}
+
+impl TryFrom<LengthSpec> for Length {
+    type Error = String;
+
+    fn try_from(spec: LengthSpec) -> Result<Self, Self::Error> {
+        let text = match spec {
+            LengthSpec::Pixels(pixels) => return Ok(Length::Pixels(pixels)),
+            LengthSpec::Text(text) => text,
+        };
+
+        let length = match text.trim().strip_suffix('%') {
+            Some(percent) => percent
+                .trim()
+                .parse()
+                .ok()
+                .filter(|percent| (0.0..=100.0).contains(percent))
+                .map(Length::Percent),
+            None => text
+                .trim()
+                .trim_end_matches("px")
+                .parse()
+                .ok()
+                .map(Length::Pixels),
+        };
+
+        length.ok_or(format!(
+            "invalid length {text:?}, expected pixels, e.g. 20, or a percentage, e.g. \"80%\""
+        ))
+    }
+}
+
+impl From<Length> for LengthSpec {
+    fn from(length: Length) -> Self {
+        match length {
+            Length::Pixels(pixels) => LengthSpec::Pixels(pixels),
+            Length::Percent(percent) => LengthSpec::Text(format!("{percent}%")),
         }
     }
 }
@@ -91,21 +256,677 @@ pub enum Position {
     Bottom,
 }
 
+/// How the space under the bar is kept free of windows.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum Reserve {
+    /// `_NET_WM_STRUT_PARTIAL` on the bar window, the WM takes care of the rest.
+    Strut,
+    /// `bspc config -m <monitor> top_padding`, for setups where struts don't work.
+    Padding,
+}
+
+/// What the bar does while the desktop shown on its monitor has a fullscreen node.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum Fullscreen {
+    /// Unmaps the bar until the fullscreen node goes away or another desktop is shown.
+    Hide,
+    /// Keeps the bar raised above the fullscreen node.
+    StayOnTop,
+    /// Keeps the bar mapped, but stacked below the fullscreen node.
+    Lower,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cpu {
+    pub mode: CpuMode,
+    pub history: usize,
+    pub show_temp: bool,
+    pub temp_sensor: Option<String>,
+    pub temp_warn: f32,
+    pub temp_alert: f32,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Cpu {
+            mode: CpuMode::Percent,
+            history: 10,
+            show_temp: false,
+            temp_sensor: None,
+            temp_warn: 70.0,
+            temp_alert: 85.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum CpuMode {
+    Percent,
+    Graph,
+    Cores,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Load {
+    pub normalize: bool,
+    pub show_uptime: bool,
+    pub warn: f64,
+    pub alert: f64,
+}
+
+impl Default for Load {
+    fn default() -> Self {
+        Load {
+            normalize: false,
+            show_uptime: false,
+            warn: 0.7,
+            alert: 1.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mem {
+    pub format: String,
+}
+
+impl Default for Mem {
+    fn default() -> Self {
+        Mem {
+            format: "MEM {percent}%".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    pub ignored_fs_types: Vec<String>,
+    pub mounts: Vec<Mount>,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            ignored_fs_types: ["tmpfs", "devtmpfs", "ramfs", "squashfs", "overlay"]
+                .map(String::from)
+                .to_vec(),
+            mounts: vec![
+                Mount {
+                    path: "/".to_string(),
+                    label: "DSK".to_string(),
+                    ..Mount::default()
+                },
+                Mount {
+                    path: "/home".to_string(),
+                    label: "HOME".to_string(),
+                    ..Mount::default()
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mount {
+    pub path: String,
+    pub label: String,
+    pub warn: f32,
+    pub alert: f32,
+    pub show_free: bool,
+}
+
+impl Default for Mount {
+    fn default() -> Self {
+        Mount {
+            path: "/".to_string(),
+            label: "DSK".to_string(),
+            warn: 85.0,
+            alert: 95.0,
+            show_free: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskIo {
+    pub devices: Vec<String>,
+    pub show_graph: bool,
+    pub history: usize,
+    pub warn: f64,
+    pub alert: f64,
+}
+
+impl Default for DiskIo {
+    fn default() -> Self {
+        DiskIo {
+            devices: vec![],
+            show_graph: false,
+            history: 10,
+            warn: 50.0,
+            alert: 200.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Clock {
+    pub format: String,
+    pub format_long: String,
+    pub timezones: Vec<String>,
+    pub timezone_mode: TimezoneMode,
+    pub calendar_dirs: Vec<String>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock {
+            format: "%H:%M".to_string(),
+            format_long: "%Y-%m-%d, %A @ %H:%M:%S".to_string(),
+            timezones: vec![],
+            timezone_mode: TimezoneMode::Cycle,
+            calendar_dirs: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum TimezoneMode {
+    Cycle,
+    SideBySide,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timer {
+    pub work_minutes: u64,
+    pub break_minutes: u64,
+    pub long_break_minutes: u64,
+    pub long_break_every: u32,
+    pub notify: bool,
+    pub command: Option<String>,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer {
+            work_minutes: 25,
+            break_minutes: 5,
+            long_break_minutes: 15,
+            long_break_every: 4,
+            notify: true,
+            command: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mpris {
+    pub players: Vec<String>,
+    pub max_length: usize,
+}
+
+impl Default for Mpris {
+    fn default() -> Self {
+        Mpris {
+            players: vec![],
+            max_length: 40,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tray {
+    pub monitor: Option<String>,
+    pub position: TrayPosition,
+    pub icon_padding: u16,
+}
+
+impl Default for Tray {
+    fn default() -> Self {
+        Tray {
+            monitor: None,
+            position: TrayPosition::Right,
+            icon_padding: 3,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum TrayPosition {
+    Left,
+    Right,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keyboard {
+    pub long_names: bool,
+    pub show_locks: bool,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Keyboard {
+            long_names: false,
+            show_locks: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backlight {
+    pub device: Option<String>,
+    pub step: u32,
+}
+
+impl Default for Backlight {
+    fn default() -> Self {
+        Backlight {
+            device: None,
+            step: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Autohide {
+    pub enabled: bool,
+    /// Monitors with an autohiding bar, all of them if empty.
+    pub monitors: Vec<String>,
+    /// Pixels of the bar left at the monitor edge while hidden, hovering them reveals the bar.
+    pub trigger_size: u16,
+    pub hide_delay_ms: u64,
+    /// Reveals the bar while held down.
+    pub modifier: Option<Modifier>,
+}
+
+impl Default for Autohide {
+    fn default() -> Self {
+        Autohide {
+            enabled: false,
+            monitors: vec![],
+            trigger_size: 2,
+            hide_delay_ms: 500,
+            modifier: None,
+        }
+    }
+}
+
+impl Autohide {
+    pub fn applies_to(&self, monitor: &str) -> bool {
+        self.enabled && (self.monitors.is_empty() || self.monitors.iter().any(|m| m == monitor))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Modifier {
+    Shift,
+    Control,
+    Alt,
+    Super,
+}
+
+/// Expands a leading `~` to the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), xdg::home()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// User styles, compiled after the built-in ones, so the theme variables are in scope.
+pub fn style_path() -> PathBuf {
+    xdg::config_dir().join("style.scss")
+}
+
+/// `--config`, otherwise the first `krowbar/config.toml` found in `$XDG_CONFIG_HOME` and
+/// `$XDG_CONFIG_DIRS`.
+pub fn path(args: &Args) -> PathBuf {
+    if let Some(path) = &args.config {
+        return path.clone();
+    }
+
+    let user_config = xdg::config_dir().join("config.toml");
+    std::iter::once(user_config.clone())
+        .chain(
+            xdg::system_config_dirs()
+                .into_iter()
+                .map(|dir| dir.join("config.toml")),
+        )
+        .find(|path| path.exists())
+        .unwrap_or(user_config)
+}
+
+/// Problems in the config don't stop the bar, they are logged, noted in `errors` and the
+/// defaults are used instead, for the broken sections or, if the TOML itself is broken, for
+/// everything.
 pub fn read(args: &Args) -> anyhow::Result<KrowbarConfig> {
-    let path = args.config.clone().unwrap_or(
-        #[allow(deprecated)] // XXX: Warning regarding Windows, we don't care now
-        std::env::home_dir()
-            .expect("Home dir not found")
-            .join(".config/krowbar/config.toml"),
-    );
+    let path = path(args);
 
-    let cfg = if fs::exists(&path)? {
-        let contents = fs::read_to_string(&path)?;
-        let config: KrowbarConfig = toml::from_str(&contents)?;
-        config
+    let mut cfg = if fs::exists(&path)? {
+        match load(&path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("{err}, falling back to the default config");
+                KrowbarConfig {
+                    errors: vec![err.to_string()],
+                    ..KrowbarConfig::default()
+                }
+            }
+        }
+    } else if args.config.is_some() {
+        let err = format!("{}: config not found", path.display());
+        log::error!("{err}, falling back to the default config");
+        KrowbarConfig {
+            errors: vec![err],
+            ..KrowbarConfig::default()
+        }
     } else {
+        log::info!("no config at {:?}, using the defaults", path);
         KrowbarConfig::default()
     };
 
+    let invalid = invalid_colors(&cfg.theme);
+    if !invalid.is_empty() {
+        for (_, err) in &invalid {
+            log::error!("{err}, falling back to the default theme");
+        }
+        cfg.errors.extend(invalid.into_iter().map(|(_, err)| err));
+        cfg.theme = Theme::default();
+    }
+
     Ok(cfg)
 }
+
+/// Everything `read` would fall back on, as `path:line:column: problem`. Unknown keys and
+/// invalid values stop the parsing, so only the first of those is reported per file.
+pub fn check(path: &Path) -> anyhow::Result<Vec<String>> {
+    let mut problems = vec![];
+    for layer in layers(path)? {
+        problems.extend(layer.check()?);
+    }
+
+    Ok(problems)
+}
+
+/// Merges the config with its includes, the including file taking precedence. Sections with
+/// unknown keys or invalid values are left out, so they get their defaults, and the problems
+/// are noted in `errors`.
+fn load(path: &Path) -> anyhow::Result<KrowbarConfig> {
+    let mut merged = toml::Table::new();
+    let mut errors = vec![];
+    for layer in layers(path)? {
+        let (sections, problems) = layer.sections()?;
+        errors.extend(problems);
+        merge(&mut merged, sections);
+    }
+    merged.remove("include");
+
+    let mut cfg: KrowbarConfig = toml::Value::Table(merged).try_into()?;
+    for err in &errors {
+        log::error!("{err}");
+    }
+    cfg.errors = errors;
+
+    Ok(cfg)
+}
+
+/// A config file, or one of the files it includes.
+struct Layer {
+    path: PathBuf,
+    contents: String,
+}
+
+impl Layer {
+    /// The top-level sections that parse on their own. `check` is the strict counterpart,
+    /// this one keeps going so a stray key doesn't cost the whole config.
+    fn sections(&self) -> anyhow::Result<(toml::Table, Vec<String>)> {
+        let mut sections: toml::Table = toml::from_str(&self.contents)
+            .map_err(|err| anyhow!(self.locate(err.span(), err.message())))?;
+        if toml::from_str::<KrowbarConfig>(&self.contents).is_ok() {
+            return Ok((sections, vec![]));
+        }
+
+        let mut problems = vec![];
+        sections.retain(|key, value| {
+            let section = toml::Table::from_iter([(key.to_string(), value.clone())]);
+            match toml::Value::Table(section).try_into::<KrowbarConfig>() {
+                Ok(_) => true,
+                Err(err) => {
+                    let message = err.message().trim_end().replace('\n', " ");
+                    problems.push(format!(
+                        "{}: {message}, ignoring `{key}`",
+                        self.path.display()
+                    ));
+                    false
+                }
+            }
+        });
+
+        Ok((sections, problems))
+    }
+
+    fn check(&self) -> anyhow::Result<Vec<String>> {
+        let cfg: KrowbarConfig = match toml::from_str(&self.contents) {
+            Ok(cfg) => cfg,
+            Err(err) => return Ok(vec![self.locate(err.span(), err.message())]),
+        };
+
+        let spans: ThemeSpans = toml::from_str(&self.contents)?;
+        let mut problems: Vec<_> = invalid_colors(&cfg.theme)
+            .into_iter()
+            .map(|(key, err)| (spans.theme.get(&key).map(|value| value.span()), err))
+            .collect();
+        problems.sort_by_key(|(span, _)| span.as_ref().map(|span| span.start));
+
+        let problems = problems
+            .into_iter()
+            .map(|(span, err)| self.locate(span, &err))
+            .collect();
+
+        Ok(problems)
+    }
+
+    fn locate(&self, span: Option<Range<usize>>, message: &str) -> String {
+        let message = message.trim_end();
+        match span {
+            Some(span) => {
+                let before = &self.contents[..span.start.min(self.contents.len())];
+                let line = before.matches('\n').count() + 1;
+                let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+                format!("{}:{line}:{column}: {message}", self.path.display())
+            }
+            None => format!("{}: {message}", self.path.display()),
+        }
+    }
+}
+
+/// The config at `path` preceded by everything it includes, depth first.
+fn layers(path: &Path) -> anyhow::Result<Vec<Layer>> {
+    fn collect(
+        path: &Path,
+        stack: &mut Vec<PathBuf>,
+        layers: &mut Vec<Layer>,
+    ) -> anyhow::Result<()> {
+        let path = path
+            .canonicalize()
+            .map_err(|err| anyhow!("{}: {err}", path.display()))?;
+        if stack.contains(&path) {
+            return Err(anyhow!("{}: includes itself", path.display()));
+        }
+
+        let contents =
+            fs::read_to_string(&path).map_err(|err| anyhow!("{}: {err}", path.display()))?;
+        // Broken files are reported by the layer checks, they just don't include anything
+        let Includes { include } = toml::from_str(&contents).unwrap_or_default();
+
+        stack.push(path.clone());
+        for included in include {
+            let dir = path.parent().unwrap_or(Path::new("/"));
+            collect(&dir.join(expand_home(&included)), stack, layers)?;
+        }
+        stack.pop();
+
+        layers.push(Layer { path, contents });
+        Ok(())
+    }
+
+    let mut layers = vec![];
+    collect(path, &mut vec![], &mut layers)?;
+    Ok(layers)
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Includes {
+    include: Vec<String>,
+}
+
+/// Tables are merged key by key, anything else, arrays included, is replaced.
+fn merge(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+                merge(base, overrides)
+            }
+            // Colors layered over a named theme tweak that theme
+            (Some(toml::Value::String(name)), toml::Value::Table(mut colors)) if key == "theme" => {
+                colors
+                    .entry("name")
+                    .or_insert(toml::Value::String(name.clone()));
+                base.insert(key, toml::Value::Table(colors));
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Options without a default value, with an example each, by section.
+const UNSET_OPTIONS: &[(&str, &str)] = &[
+    (
+        "cpu",
+        r#"temp_sensor = "Package id 0" # Sensor label to read, defaults to the first package sensor found"#,
+    ),
+    (
+        "timer",
+        r#"command = "paplay ~/bell.ogg" # Run when a phase ends, gets $KROWBAR_ENDED_PHASE and $KROWBAR_NEXT_PHASE"#,
+    ),
+    (
+        "tray",
+        r#"monitor = "DP-1" # Monitor hosting the XEmbed tray, defaults to the primary one"#,
+    ),
+    (
+        "backlight",
+        r#"device = "intel_backlight" # Directory in /sys/class/backlight, defaults to the first one found"#,
+    ),
+    (
+        "autohide",
+        r#"modifier = "Super" # Shift, Control, Alt or Super, keeps the bar revealed while held"#,
+    ),
+];
+
+/// Commented out, as all of the values are optional anyway.
+pub fn default_toml() -> anyhow::Result<String> {
+    let defaults = toml::to_string_pretty(&KrowbarConfig::default())?;
+
+    let mut commented = String::from(
+        "# Default krowbar config, uncomment and change the values you need.\n\
+         # See https://github.com/bloznelis/krowbar#config for what they do.\n\n",
+    );
+    for line in defaults.lines() {
+        match line {
+            "" => commented.push('\n'),
+            line => commented.push_str(&format!("# {line}\n")),
+        }
+        // Unset options don't serialize, so they are listed after their section header
+        let section = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'));
+        for (_, option) in UNSET_OPTIONS
+            .iter()
+            .filter(|(name, _)| Some(*name) == section)
+        {
+            commented.push_str(&format!("# {option}\n"));
+        }
+    }
+
+    Ok(commented)
+}
+
+/// Locations of the theme colors, so invalid ones can be pointed at.
+#[derive(Deserialize)]
+struct ThemeSpans {
+    #[serde(default, deserialize_with = "deserialize_theme_spans")]
+    theme: BTreeMap<String, toml::Spanned<toml::Value>>,
+}
+
+fn deserialize_theme_spans<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, toml::Spanned<toml::Value>>, D::Error> {
+    struct ThemeVisitor;
+
+    impl<'de> Visitor<'de> for ThemeVisitor {
+        type Value = BTreeMap<String, toml::Spanned<toml::Value>>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a theme name or a table of colors")
+        }
+
+        // Named themes are valid as is
+        fn visit_str<E: Error>(self, _: &str) -> Result<Self::Value, E> {
+            Ok(BTreeMap::new())
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut spans = BTreeMap::new();
+            while let Some((key, value)) = map.next_entry()? {
+                spans.insert(key, value);
+            }
+            Ok(spans)
+        }
+    }
+
+    deserializer.deserialize_any(ThemeVisitor)
+}
+
+/// Theme colors end up in the generated SCSS as is, so anything that isn't a color breaks the
+/// whole stylesheet. Returns the offending keys along with the problem.
+pub fn invalid_colors(theme: &Theme) -> Vec<(String, String)> {
+    let Ok(toml::Value::Table(colors)) = toml::Value::try_from(theme) else {
+        return vec![];
+    };
+
+    colors
+        .iter()
+        .filter_map(|(key, value)| match value.as_str() {
+            Some(color) if is_color(color) => None,
+            _ => Some((key.clone(), format!("theme.{key}: invalid color {value}"))),
+        })
+        .collect()
+}
+
+/// `#rgb`, `#rgba`, `#rrggbb`, `#rrggbbaa`, `rgb()`/`rgba()`/`hsl()`/`hsla()` or a named color.
+/// grass is the one to tell, so typos like `reed` get caught here rather than in the theme.
+pub fn is_color(color: &str) -> bool {
+    // Would let the value break out of the declaration it's put in
+    if color.contains([';', '{', '}']) {
+        return false;
+    }
+
+    // `red()` only takes colors
+    let probe = format!("a {{ b: red({color}); }}");
+    grass::from_string(probe, &grass::Options::default()).is_ok()
+}
@@ -0,0 +1,71 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use gtk4::gio::{self, prelude::*};
+
+const BACKLIGHT_DIR: &str = "/sys/class/backlight";
+const TIMEOUT_MS: i32 = 200;
+
+#[derive(Clone)]
+pub struct BacklightDevice {
+    pub name: String,
+    path: PathBuf,
+}
+
+impl BacklightDevice {
+    /// The configured device, or the first one found, e.g. `intel_backlight`.
+    pub fn find(name: Option<&str>) -> anyhow::Result<BacklightDevice> {
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => fs::read_dir(BACKLIGHT_DIR)?
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .min()
+                .ok_or(anyhow!("No backlight devices found"))?,
+        };
+
+        let path = Path::new(BACKLIGHT_DIR).join(&name);
+        if !path.exists() {
+            return Err(anyhow!("Backlight device {name} not found"));
+        }
+
+        Ok(BacklightDevice { name, path })
+    }
+
+    pub fn brightness_path(&self) -> PathBuf {
+        self.path.join("brightness")
+    }
+
+    pub fn brightness(&self) -> anyhow::Result<u32> {
+        Self::read_value(&self.brightness_path())
+    }
+
+    pub fn max_brightness(&self) -> anyhow::Result<u32> {
+        Self::read_value(&self.path.join("max_brightness"))
+    }
+
+    fn read_value(path: &Path) -> anyhow::Result<u32> {
+        Ok(fs::read_to_string(path)?.trim().parse()?)
+    }
+
+    /// Goes through logind, so we don't need write access to sysfs.
+    pub async fn set_brightness(&self, brightness: u32) -> anyhow::Result<()> {
+        let conn = gio::bus_get_future(gio::BusType::System).await?;
+        conn.call_future(
+            Some("org.freedesktop.login1"),
+            "/org/freedesktop/login1/session/auto",
+            "org.freedesktop.login1.Session",
+            "SetBrightness",
+            Some(&("backlight", self.name.as_str(), brightness).to_variant()),
+            None,
+            gio::DBusCallFlags::NONE,
+            TIMEOUT_MS,
+        )
+        .await?;
+
+        Ok(())
+    }
+}
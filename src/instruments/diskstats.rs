@@ -0,0 +1,58 @@
+use std::fs;
+
+use anyhow::anyhow;
+
+// `/proc/diskstats` always counts in 512 byte sectors, regardless of the device
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskCounters {
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+}
+
+/// Sums read and written bytes of the given block devices. When `devices` is empty, all
+/// whole physical disks are taken into account.
+pub fn read_counters(devices: &[String]) -> anyhow::Result<DiskCounters> {
+    let contents = fs::read_to_string("/proc/diskstats")?;
+    let mut counters = DiskCounters::default();
+
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let name = *fields
+            .get(2)
+            .ok_or(anyhow!("Malformed diskstats line: {line}"))?;
+
+        let is_tracked = if devices.is_empty() {
+            is_physical_disk(name)
+        } else {
+            devices.iter().any(|device| device == name)
+        };
+
+        if is_tracked {
+            let sectors_read: u64 = fields
+                .get(5)
+                .ok_or(anyhow!("Missing sectors read for {name}"))?
+                .parse()?;
+            let sectors_written: u64 = fields
+                .get(9)
+                .ok_or(anyhow!("Missing sectors written for {name}"))?
+                .parse()?;
+
+            counters.read_bytes += sectors_read * SECTOR_SIZE;
+            counters.written_bytes += sectors_written * SECTOR_SIZE;
+        }
+    }
+
+    Ok(counters)
+}
+
+fn is_physical_disk(name: &str) -> bool {
+    // Partitions are not listed directly under /sys/block
+    let is_whole_disk = fs::exists(format!("/sys/block/{name}")).unwrap_or(false);
+    let is_virtual = ["loop", "ram", "zram", "dm-", "md"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix));
+
+    is_whole_disk && !is_virtual
+}
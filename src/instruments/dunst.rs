@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use gtk4::gio::{self, prelude::*};
+use gtk4::glib::{Variant, VariantTy};
+
+const DUNST_NAME: &str = "org.freedesktop.Notifications";
+const DUNST_PATH: &str = "/org/freedesktop/Notifications";
+// dunst specific interface, the one dunstctl talks to
+const DUNST_INTERFACE: &str = "org.dunstproject.cmd0";
+// Polled on the UI thread, so a stuck daemon must not freeze the bar
+const TIMEOUT_MS: i32 = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DunstStatus {
+    pub paused: bool,
+    /// Notifications held back while paused.
+    pub waiting: u32,
+}
+
+pub struct DunstClient {
+    conn: gio::DBusConnection,
+}
+
+impl DunstClient {
+    pub fn new() -> anyhow::Result<DunstClient> {
+        let conn = gio::bus_get_sync(gio::BusType::Session, None::<&gio::Cancellable>)?;
+        Ok(DunstClient { conn })
+    }
+
+    pub fn status(&self) -> anyhow::Result<DunstStatus> {
+        let (properties,): (HashMap<String, Variant>,) = self
+            .conn
+            .call_sync(
+                Some(DUNST_NAME),
+                DUNST_PATH,
+                "org.freedesktop.DBus.Properties",
+                "GetAll",
+                Some(&(DUNST_INTERFACE,).to_variant()),
+                Some(VariantTy::new("(a{sv})")?),
+                gio::DBusCallFlags::NO_AUTO_START,
+                TIMEOUT_MS,
+                None::<&gio::Cancellable>,
+            )?
+            .get()
+            .ok_or(anyhow!("Unexpected GetAll reply from dunst"))?;
+
+        Ok(DunstStatus {
+            paused: properties
+                .get("paused")
+                .and_then(|paused| paused.get::<bool>())
+                .ok_or(anyhow!("dunst did not report its paused state"))?,
+            waiting: properties
+                .get("waitingLength")
+                .and_then(|waiting| waiting.get::<u32>())
+                .unwrap_or(0),
+        })
+    }
+
+    /// Same as `dunstctl set-paused`.
+    pub fn set_paused(&self, paused: bool) -> anyhow::Result<()> {
+        self.conn.call_sync(
+            Some(DUNST_NAME),
+            DUNST_PATH,
+            "org.freedesktop.DBus.Properties",
+            "Set",
+            Some(&(DUNST_INTERFACE, "paused", paused.to_variant()).to_variant()),
+            None,
+            gio::DBusCallFlags::NO_AUTO_START,
+            TIMEOUT_MS,
+            None::<&gio::Cancellable>,
+        )?;
+
+        Ok(())
+    }
+
+    /// Calls `on_change` when dunst gets paused or unpaused, e.g. via dunstctl.
+    pub fn watch(&self, on_change: impl Fn() + 'static) {
+        self.conn.signal_subscribe(
+            None,
+            Some("org.freedesktop.DBus.Properties"),
+            Some("PropertiesChanged"),
+            Some(DUNST_PATH),
+            Some(DUNST_INTERFACE),
+            gio::DBusSignalFlags::NONE,
+            move |_, _, _, _, _, _| on_change(),
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use gtk4::glib::{DateTime, TimeZone};
+
+#[derive(Debug, Clone)]
+pub struct CalendarEvent {
+    pub summary: String,
+    pub start: DateTime,
+    pub all_day: bool,
+}
+
+/// Returns the given directories together with their direct subdirectories. vdirsyncer
+/// keeps one subdirectory per calendar, so pointing to the storage root is enough.
+pub fn calendar_dirs(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    dirs.iter()
+        .flat_map(|dir| {
+            let subdirs: Vec<PathBuf> = fs::read_dir(dir)
+                .map(|entries| {
+                    entries
+                        .flatten()
+                        .map(|entry| entry.path())
+                        .filter(|path| path.is_dir())
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            std::iter::once(dir.clone()).chain(subdirs)
+        })
+        .collect()
+}
+
+pub fn read_events(dirs: &[PathBuf]) -> Vec<CalendarEvent> {
+    let mut events: Vec<CalendarEvent> = calendar_dirs(dirs)
+        .iter()
+        .flat_map(|dir| fs::read_dir(dir).into_iter().flatten().flatten())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ics"))
+        .flat_map(|path| match read_file(&path) {
+            Ok(events) => events,
+            Err(err) => {
+                log::error!("Failed to read calendar file {:?} {err}", path);
+                vec![]
+            }
+        })
+        .collect();
+
+    events.sort_by(|event1, event2| event1.start.cmp(&event2.start));
+    events
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Vec<CalendarEvent>> {
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+// XXX: recurring events (RRULE) only show up on their first occurrence
+pub fn parse(contents: &str) -> Vec<CalendarEvent> {
+    let mut events = vec![];
+    let mut components: Vec<String> = vec![];
+    let mut summary = String::new();
+    let mut start: Option<(DateTime, bool)> = None;
+
+    for line in unfold(contents) {
+        let Some((name, params, value)) = split_property(&line) else {
+            continue;
+        };
+
+        match name {
+            "BEGIN" => {
+                if value == "VEVENT" {
+                    summary = String::new();
+                    start = None;
+                }
+                components.push(value.to_string());
+            }
+            "END" => {
+                if value == "VEVENT" {
+                    if let Some((start, all_day)) = start.take() {
+                        events.push(CalendarEvent {
+                            summary: std::mem::take(&mut summary),
+                            start,
+                            all_day,
+                        });
+                    }
+                }
+                components.pop();
+            }
+            // Alarms have their own summaries, we only care about the event ones
+            _ if components.last().map(String::as_str) != Some("VEVENT") => {}
+            "SUMMARY" => summary = unescape(value),
+            "DTSTART" => start = parse_date_time(&params, value),
+            _ => {}
+        }
+    }
+
+    events
+}
+
+fn unfold(contents: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in contents.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Parameter names with their values, e.g. `TZID` and `Europe/Berlin`.
+type Params<'a> = Vec<(&'a str, &'a str)>;
+
+/// Splits a content line into its name, parameters and value. Parameter values may be
+/// quoted to hold `:` and `;`, the quotes are dropped.
+fn split_property(line: &str) -> Option<(&str, Params<'_>, &str)> {
+    let mut quoted = false;
+    let mut separators = line.char_indices().filter(|&(_, c)| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        !quoted && (c == ';' || c == ':')
+    });
+
+    let mut params = vec![];
+    let mut from = 0;
+    let mut name = None;
+    loop {
+        let (index, separator) = separators.next()?;
+        let part = &line[from..index];
+        match name {
+            None => name = Some(part),
+            Some(_) => {
+                let (param, value) = part.split_once('=').unwrap_or((part, ""));
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                params.push((param, value));
+            }
+        }
+        from = index + 1;
+        if separator == ':' {
+            return Some((name?, params, &line[from..]));
+        }
+    }
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Summaries are shown on a single line
+            Some('n' | 'N') => unescaped.push(' '),
+            Some(escaped) => unescaped.push(escaped),
+            None => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+fn parse_date_time(params: &[(&str, &str)], value: &str) -> Option<(DateTime, bool)> {
+    let param = |name: &str| {
+        params
+            .iter()
+            .find_map(|&(param, value)| (param == name).then_some(value))
+    };
+    let tzid = param("TZID");
+    let all_day = param("VALUE") == Some("DATE") || value.len() == 8;
+
+    let number = |from: usize, to: usize| value.get(from..to)?.parse::<i32>().ok();
+    let (year, month, day) = (number(0, 4)?, number(4, 6)?, number(6, 8)?);
+
+    if all_day {
+        let date = DateTime::new(&TimeZone::local(), year, month, day, 0, 0, 0.).ok()?;
+        return Some((date, true));
+    }
+
+    let (hour, minute, second) = (number(9, 11)?, number(11, 13)?, number(13, 15)?);
+    let tz = match tzid {
+        _ if value.ends_with('Z') => TimeZone::utc(),
+        Some(tzid) => TimeZone::new(Some(tzid)),
+        // Floating time, i.e. the same wall clock time in any timezone
+        None => TimeZone::local(),
+    };
+
+    let date = DateTime::new(&tz, year, month, day, hour, minute, second as f64)
+        .and_then(|date| date.to_local())
+        .ok()?;
+
+    Some((date, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(properties: &str) -> CalendarEvent {
+        let contents = format!(
+            "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\n{properties}END:VEVENT\r\nEND:VCALENDAR\r\n"
+        );
+        let mut events = parse(&contents);
+        assert_eq!(events.len(), 1);
+        events.remove(0)
+    }
+
+    #[test]
+    fn unfolds_continuation_lines() {
+        let event =
+            event("SUMMARY:Weekly\r\n  sync\r\n\twith team\r\nDTSTART:20240115T100000Z\r\n");
+        assert_eq!(event.summary, "Weekly syncwith team");
+    }
+
+    #[test]
+    fn unquotes_tzid() {
+        let event = event("DTSTART;TZID=\"Europe/Berlin\":20240115T100000\r\n");
+        let start = event.start.to_utc().unwrap();
+        assert!(!event.all_day);
+        assert_eq!((start.day_of_month(), start.hour()), (15, 9));
+    }
+
+    #[test]
+    fn keeps_separators_in_quoted_params() {
+        let event = event("SUMMARY;X-NOTE=\"a;b:c\":Review\r\nDTSTART:20240115T100000Z\r\n");
+        assert_eq!(event.summary, "Review");
+    }
+
+    #[test]
+    fn unescapes_text_in_one_pass() {
+        let summary = r"SUMMARY:a\, b\; c\nd \\n e\\";
+        let event = event(&format!("{summary}\r\nDTSTART:20240115T100000Z\r\n"));
+        assert_eq!(event.summary, r"a, b; c d \n e\");
+    }
+
+    #[test]
+    fn parses_all_day_events() {
+        let event = event("SUMMARY:Holiday\r\nDTSTART;VALUE=DATE:20240704\r\n");
+        assert!(event.all_day);
+        assert_eq!(
+            (
+                event.start.year(),
+                event.start.month(),
+                event.start.day_of_month()
+            ),
+            (2024, 7, 4)
+        );
+        assert_eq!(event.start.hour(), 0);
+    }
+
+    #[test]
+    fn skips_alarm_summaries() {
+        let event = event(
+            "SUMMARY:Dentist\r\nDTSTART:20240115T100000Z\r\n\
+             BEGIN:VALARM\r\nSUMMARY:Reminder\r\nEND:VALARM\r\n",
+        );
+        assert_eq!(event.summary, "Dentist");
+    }
+}
@@ -1 +1,8 @@
 pub mod alsa;
+pub mod backlight;
+pub mod diskstats;
+pub mod dunst;
+pub mod ical;
+pub mod mpris;
+pub mod pomodoro;
+pub mod sni;
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use gtk4::gio::{self, prelude::*};
+use gtk4::glib::{Variant, VariantTy};
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+// Players are queried one after another, so a stuck one must not hold up the rest
+const TIMEOUT_MS: i32 = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayerState {
+    pub bus_name: String,
+    pub status: PlaybackStatus,
+    pub artist: String,
+    pub title: String,
+}
+
+impl PlayerState {
+    /// Bus name without the MPRIS prefix, e.g. `spotify` or `firefox.instance_1_42`.
+    pub fn short_name(&self) -> &str {
+        self.bus_name
+            .strip_prefix(MPRIS_PREFIX)
+            .unwrap_or(&self.bus_name)
+    }
+}
+
+pub struct MprisClient {
+    conn: gio::DBusConnection,
+}
+
+impl MprisClient {
+    pub fn new() -> anyhow::Result<MprisClient> {
+        let conn = gio::bus_get_sync(gio::BusType::Session, None::<&gio::Cancellable>)?;
+        Ok(MprisClient { conn })
+    }
+
+    pub async fn players(&self) -> anyhow::Result<Vec<PlayerState>> {
+        let (names,): (Vec<String>,) = self
+            .conn
+            .call_future(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                "ListNames",
+                None,
+                Some(VariantTy::new("(as)")?),
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+            )
+            .await?
+            .get()
+            .ok_or(anyhow!("Unexpected ListNames reply"))?;
+
+        let mut players = vec![];
+        for name in names
+            .into_iter()
+            .filter(|name| name.starts_with(MPRIS_PREFIX))
+        {
+            match self.player_state(name).await {
+                Ok(player) => players.push(player),
+                Err(err) => log::error!("Failed to query MPRIS player {err}"),
+            }
+        }
+
+        Ok(players)
+    }
+
+    async fn player_state(&self, bus_name: String) -> anyhow::Result<PlayerState> {
+        let (properties,): (HashMap<String, Variant>,) = self
+            .conn
+            .call_future(
+                Some(&bus_name),
+                MPRIS_PATH,
+                "org.freedesktop.DBus.Properties",
+                "GetAll",
+                Some(&(PLAYER_INTERFACE,).to_variant()),
+                Some(VariantTy::new("(a{sv})")?),
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+            )
+            .await?
+            .get()
+            .ok_or(anyhow!("Unexpected GetAll reply from {bus_name}"))?;
+
+        let status = match properties
+            .get("PlaybackStatus")
+            .and_then(|status| status.get::<String>())
+            .as_deref()
+        {
+            Some("Playing") => PlaybackStatus::Playing,
+            Some("Paused") => PlaybackStatus::Paused,
+            _ => PlaybackStatus::Stopped,
+        };
+
+        let metadata: HashMap<String, Variant> = properties
+            .get("Metadata")
+            .and_then(|metadata| metadata.get())
+            .unwrap_or_default();
+        let artist = metadata
+            .get("xesam:artist")
+            .and_then(|artists| artists.get::<Vec<String>>())
+            .map(|artists| artists.join(", "))
+            .unwrap_or_default();
+        let title = metadata
+            .get("xesam:title")
+            .and_then(|title| title.get::<String>())
+            .unwrap_or_default();
+
+        Ok(PlayerState {
+            bus_name,
+            status,
+            artist,
+            title,
+        })
+    }
+
+    /// Calls one of the argumentless player methods, e.g. `PlayPause` or `Next`.
+    pub async fn call(&self, bus_name: &str, method: &str) -> anyhow::Result<()> {
+        self.conn
+            .call_future(
+                Some(bus_name),
+                MPRIS_PATH,
+                PLAYER_INTERFACE,
+                method,
+                None,
+                None,
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Calls `on_change` whenever a player appears, disappears or changes its state.
+    pub fn watch(&self, on_change: impl Fn() + Clone + 'static) {
+        let on_owner_change = on_change.clone();
+        self.conn.signal_subscribe(
+            Some("org.freedesktop.DBus"),
+            Some("org.freedesktop.DBus"),
+            Some("NameOwnerChanged"),
+            Some("/org/freedesktop/DBus"),
+            Some("org.mpris.MediaPlayer2"),
+            gio::DBusSignalFlags::MATCH_ARG0_NAMESPACE,
+            move |_, _, _, _, _, _| on_owner_change(),
+        );
+
+        self.conn.signal_subscribe(
+            None,
+            Some("org.freedesktop.DBus.Properties"),
+            Some("PropertiesChanged"),
+            Some(MPRIS_PATH),
+            Some(PLAYER_INTERFACE),
+            gio::DBusSignalFlags::NONE,
+            move |_, _, _, _, _, _| on_change(),
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::{Duration, Instant};
+
+use crate::{config, TimerAction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Work,
+    Break,
+    LongBreak,
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Work => "work",
+            Phase::Break => "break",
+            Phase::LongBreak => "long-break",
+        }
+    }
+}
+
+/// Pomodoro state shared by the timer widgets of all the bars.
+pub struct Pomodoro {
+    cfg: config::Timer,
+    phase: Phase,
+    duration: Duration,
+    // Time left at the moment the timer was last paused
+    remaining: Duration,
+    running_since: Option<Instant>,
+    finished_work_phases: u32,
+}
+
+impl Pomodoro {
+    const STEP: Duration = Duration::from_secs(60);
+
+    pub fn new(cfg: &config::Timer) -> Self {
+        let duration = Self::phase_duration(cfg, Phase::Work);
+        Pomodoro {
+            cfg: cfg.clone(),
+            phase: Phase::Work,
+            duration,
+            remaining: duration,
+            running_since: None,
+            finished_work_phases: 0,
+        }
+    }
+
+    /// Takes the durations of a reloaded config from the next phase on, an idle timer starts
+    /// over with them right away.
+    pub fn reconfigure(&mut self, cfg: &config::Timer) {
+        if self.is_idle() {
+            *self = Pomodoro::new(cfg);
+        } else {
+            self.cfg = cfg.clone();
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running_since.is_some()
+    }
+
+    /// Timer that was never started, or was reset.
+    pub fn is_idle(&self) -> bool {
+        !self.is_running() && self.phase == Phase::Work && self.remaining == self.duration
+    }
+
+    pub fn remaining(&self) -> Duration {
+        match self.running_since {
+            Some(since) => self.remaining.saturating_sub(since.elapsed()),
+            None => self.remaining,
+        }
+    }
+
+    pub fn apply(&mut self, action: TimerAction) {
+        match action {
+            TimerAction::Start => self.start(),
+            TimerAction::Pause => self.pause(),
+            TimerAction::Toggle => {
+                if self.is_running() {
+                    self.pause()
+                } else {
+                    self.start()
+                }
+            }
+            TimerAction::Reset => self.reset(),
+        }
+    }
+
+    fn start(&mut self) {
+        if !self.is_running() {
+            self.running_since = Some(Instant::now());
+        }
+    }
+
+    fn pause(&mut self) {
+        self.remaining = self.remaining();
+        self.running_since = None;
+    }
+
+    fn reset(&mut self) {
+        *self = Pomodoro::new(&self.cfg);
+    }
+
+    /// Prolongs (or shortens) the current phase by a minute.
+    pub fn adjust(&mut self, longer: bool) {
+        let remaining = self.remaining();
+        let (duration, remaining) = if longer {
+            (self.duration + Self::STEP, remaining + Self::STEP)
+        } else if remaining > Self::STEP {
+            (
+                self.duration.saturating_sub(Self::STEP),
+                remaining - Self::STEP,
+            )
+        } else {
+            (self.duration, remaining)
+        };
+
+        self.duration = duration;
+        self.remaining = remaining;
+        if self.is_running() {
+            self.running_since = Some(Instant::now());
+        }
+    }
+
+    /// Moves to the next phase once the current one runs out. Returns the phase which just
+    /// ended, so callers can react to it exactly once.
+    pub fn update(&mut self) -> Option<Phase> {
+        if !self.is_running() || !self.remaining().is_zero() {
+            return None;
+        }
+
+        let ended = self.phase;
+        self.phase = match ended {
+            Phase::Work => {
+                self.finished_work_phases += 1;
+                if self.cfg.long_break_every > 0
+                    && self
+                        .finished_work_phases
+                        .is_multiple_of(self.cfg.long_break_every)
+                {
+                    Phase::LongBreak
+                } else {
+                    Phase::Break
+                }
+            }
+            Phase::Break | Phase::LongBreak => Phase::Work,
+        };
+        self.duration = Self::phase_duration(&self.cfg, self.phase);
+        self.remaining = self.duration;
+        self.running_since = Some(Instant::now());
+
+        self.run_hooks(ended);
+        Some(ended)
+    }
+
+    // tokio takes care of reaping the spawned processes
+    fn run_hooks(&self, ended: Phase) {
+        if self.cfg.notify {
+            let _ = tokio::process::Command::new("notify-send")
+                .arg("krowbar")
+                .arg(format!(
+                    "{} is over, {} starts",
+                    ended.name(),
+                    self.phase.name()
+                ))
+                .spawn()
+                .inspect_err(|err| log::error!("Failed to send timer notification {err}"));
+        }
+
+        if let Some(command) = &self.cfg.command {
+            let _ = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("KROWBAR_ENDED_PHASE", ended.name())
+                .env("KROWBAR_NEXT_PHASE", self.phase.name())
+                .spawn()
+                .inspect_err(|err| log::error!("Failed to run timer command {err}"));
+        }
+    }
+
+    fn phase_duration(cfg: &config::Timer, phase: Phase) -> Duration {
+        let minutes = match phase {
+            Phase::Work => cfg.work_minutes,
+            Phase::Break => cfg.break_minutes,
+            Phase::LongBreak => cfg.long_break_minutes,
+        };
+
+        Duration::from_secs(minutes * 60)
+    }
+}
@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use gtk4::gio::{self, prelude::*};
+use gtk4::glib::{Variant, VariantTy};
+
+const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+const WATCHER_INTERFACE: &str = "org.kde.StatusNotifierWatcher";
+const ITEM_INTERFACE: &str = "org.kde.StatusNotifierItem";
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU_INTERFACE: &str = "com.canonical.dbusmenu";
+// Items are queried on the UI thread, so a stuck one must not freeze the bar
+const TIMEOUT_MS: i32 = 200;
+
+const WATCHER_XML: &str = r#"
+<node>
+  <interface name="org.kde.StatusNotifierWatcher">
+    <method name="RegisterStatusNotifierItem">
+      <arg name="service" type="s" direction="in"/>
+    </method>
+    <method name="RegisterStatusNotifierHost">
+      <arg name="service" type="s" direction="in"/>
+    </method>
+    <property name="RegisteredStatusNotifierItems" type="as" access="read"/>
+    <property name="IsStatusNotifierHostRegistered" type="b" access="read"/>
+    <property name="ProtocolVersion" type="i" access="read"/>
+    <signal name="StatusNotifierItemRegistered">
+      <arg name="service" type="s"/>
+    </signal>
+    <signal name="StatusNotifierItemUnregistered">
+      <arg name="service" type="s"/>
+    </signal>
+    <signal name="StatusNotifierHostRegistered"/>
+  </interface>
+</node>
+"#;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemStatus {
+    Passive,
+    Active,
+    NeedsAttention,
+}
+
+/// ARGB32 image in network byte order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pixmap {
+    pub width: i32,
+    pub height: i32,
+    pub argb: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemState {
+    /// Bus name followed by the object path, as registered with the watcher.
+    pub service: String,
+    pub title: String,
+    pub status: ItemStatus,
+    pub icon_name: String,
+    pub icon_theme_path: String,
+    pub icon_pixmaps: Vec<Pixmap>,
+    pub menu: Option<String>,
+    pub item_is_menu: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MenuItem {
+    pub id: i32,
+    pub label: String,
+    pub enabled: bool,
+    pub separator: bool,
+    pub toggled: Option<bool>,
+    pub children: Vec<MenuItem>,
+}
+
+/// Runs the StatusNotifierWatcher, unless some other one (e.g. KDE's) is already running, and
+/// registers us as a host, so apps know their items get shown.
+pub fn start_watcher() {
+    let items: Rc<Mutex<Vec<String>>> = Rc::default();
+    gio::bus_own_name(
+        gio::BusType::Session,
+        WATCHER_NAME,
+        gio::BusNameOwnerFlags::NONE,
+        move |conn, _| {
+            if let Err(err) = register_watcher(&conn, items.clone()) {
+                log::error!("Failed to register the StatusNotifierWatcher {:?}", err);
+            }
+        },
+        |_, _| log::info!("acting as the StatusNotifierWatcher"),
+        |_, _| log::info!("another StatusNotifierWatcher is running, using it instead"),
+    );
+
+    let host_name = format!("org.kde.StatusNotifierHost-{}", std::process::id());
+    gio::bus_own_name(
+        gio::BusType::Session,
+        &host_name,
+        gio::BusNameOwnerFlags::NONE,
+        |_, _| {},
+        |conn, name| {
+            // Async, the watcher might be us, answering on this very thread
+            conn.call(
+                Some(WATCHER_NAME),
+                WATCHER_PATH,
+                WATCHER_INTERFACE,
+                "RegisterStatusNotifierHost",
+                Some(&(name,).to_variant()),
+                None,
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+                None::<&gio::Cancellable>,
+                |result| {
+                    if let Err(err) = result {
+                        log::error!("Failed to register as a StatusNotifierHost {err}");
+                    }
+                },
+            );
+        },
+        |_, _| log::error!("Failed to own the StatusNotifierHost name"),
+    );
+}
+
+fn register_watcher(
+    conn: &gio::DBusConnection,
+    items: Rc<Mutex<Vec<String>>>,
+) -> anyhow::Result<()> {
+    let node = gio::DBusNodeInfo::for_xml(WATCHER_XML)?;
+    let interface = node.lookup_interface(WATCHER_INTERFACE).ok_or(anyhow!(
+        "Watcher interface missing from the introspection data"
+    ))?;
+
+    let registered_items = items.clone();
+    let property_items = items.clone();
+    conn.register_object(WATCHER_PATH, &interface)
+        .method_call(move |conn, sender, _, _, method, params, invocation| {
+            match method {
+                "RegisterStatusNotifierItem" => {
+                    let (service,): (String,) = params.get().unwrap_or_default();
+                    // Ayatana based apps register with just the object path
+                    let service = if service.starts_with('/') {
+                        format!("{sender}{service}")
+                    } else {
+                        service
+                    };
+
+                    let mut items = registered_items.lock().expect("sni items mutex");
+                    if !items.contains(&service) {
+                        items.push(service.clone());
+                        emit_watcher_signal(
+                            &conn,
+                            "StatusNotifierItemRegistered",
+                            Some(&(service,).to_variant()),
+                        );
+                    }
+                    invocation.return_value(None);
+                }
+                "RegisterStatusNotifierHost" => {
+                    emit_watcher_signal(&conn, "StatusNotifierHostRegistered", None);
+                    invocation.return_value(None);
+                }
+                _ => invocation.return_dbus_error(
+                    "org.freedesktop.DBus.Error.UnknownMethod",
+                    &format!("Unknown method {method}"),
+                ),
+            }
+        })
+        .property(move |_, _, _, _, property| match property {
+            "RegisteredStatusNotifierItems" => {
+                property_items.lock().expect("sni items mutex").to_variant()
+            }
+            "IsStatusNotifierHostRegistered" => true.to_variant(),
+            _ => 0i32.to_variant(),
+        })
+        .build()?;
+
+    // Items never unregister, they just drop off the bus
+    conn.signal_subscribe(
+        Some("org.freedesktop.DBus"),
+        Some("org.freedesktop.DBus"),
+        Some("NameOwnerChanged"),
+        Some("/org/freedesktop/DBus"),
+        None,
+        gio::DBusSignalFlags::NONE,
+        move |conn, _, _, _, _, params| {
+            let Some((name, _, new_owner)) = params.get::<(String, String, String)>() else {
+                return;
+            };
+            if !new_owner.is_empty() {
+                return;
+            }
+
+            let mut items = items.lock().expect("sni items mutex");
+            let (gone, kept): (Vec<String>, Vec<String>) = items
+                .drain(..)
+                .partition(|service| bus_and_path(service).0 == name);
+            *items = kept;
+
+            for service in gone {
+                emit_watcher_signal(
+                    conn,
+                    "StatusNotifierItemUnregistered",
+                    Some(&(service,).to_variant()),
+                );
+            }
+        },
+    );
+
+    Ok(())
+}
+
+fn emit_watcher_signal(conn: &gio::DBusConnection, signal: &str, params: Option<&Variant>) {
+    if let Err(err) = conn.emit_signal(None, WATCHER_PATH, WATCHER_INTERFACE, signal, params) {
+        log::error!("Failed to emit {signal} {err}");
+    }
+}
+
+/// Splits `:1.42/org/ayatana/NotificationItem/nm_applet` into the bus name and object path.
+fn bus_and_path(service: &str) -> (&str, &str) {
+    match service.find('/') {
+        Some(idx) => service.split_at(idx),
+        None => (service, ITEM_PATH),
+    }
+}
+
+pub struct SniClient {
+    conn: gio::DBusConnection,
+}
+
+impl SniClient {
+    pub fn new() -> anyhow::Result<SniClient> {
+        let conn = gio::bus_get_sync(gio::BusType::Session, None::<&gio::Cancellable>)?;
+        Ok(SniClient { conn })
+    }
+
+    // Async, as the watcher might be us, answering on this very thread
+    pub async fn items(&self) -> anyhow::Result<Vec<ItemState>> {
+        let services: Vec<String> = self
+            .conn
+            .call_future(
+                Some(WATCHER_NAME),
+                WATCHER_PATH,
+                "org.freedesktop.DBus.Properties",
+                "Get",
+                Some(&(WATCHER_INTERFACE, "RegisteredStatusNotifierItems").to_variant()),
+                Some(VariantTy::new("(v)")?),
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+            )
+            .await?
+            .child_value(0)
+            .as_variant()
+            .and_then(|services| services.get())
+            .ok_or(anyhow!("Unexpected RegisteredStatusNotifierItems reply"))?;
+
+        let mut items = vec![];
+        for service in services {
+            match self.item_state(service).await {
+                Ok(item) => items.push(item),
+                Err(err) => log::error!("Failed to query tray item {err}"),
+            }
+        }
+
+        Ok(items)
+    }
+
+    async fn item_state(&self, service: String) -> anyhow::Result<ItemState> {
+        let (bus_name, path) = bus_and_path(&service);
+        let (properties,): (HashMap<String, Variant>,) = self
+            .conn
+            .call_future(
+                Some(bus_name),
+                path,
+                "org.freedesktop.DBus.Properties",
+                "GetAll",
+                Some(&(ITEM_INTERFACE,).to_variant()),
+                Some(VariantTy::new("(a{sv})")?),
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+            )
+            .await?
+            .get()
+            .ok_or(anyhow!("Unexpected GetAll reply from {service}"))?;
+
+        let string = |name: &str| {
+            properties
+                .get(name)
+                .and_then(|value| value.str().map(String::from))
+                .unwrap_or_default()
+        };
+
+        let status = match string("Status").as_str() {
+            "Passive" => ItemStatus::Passive,
+            "NeedsAttention" => ItemStatus::NeedsAttention,
+            _ => ItemStatus::Active,
+        };
+
+        // The tooltip title tends to be more descriptive than the item title
+        let tooltip = properties
+            .get("ToolTip")
+            .filter(|tooltip| tooltip.n_children() == 4)
+            .and_then(|tooltip| tooltip.child_value(2).str().map(String::from))
+            .filter(|title| !title.is_empty());
+
+        let icon_pixmaps = properties
+            .get("IconPixmap")
+            .and_then(|pixmaps| pixmaps.get::<Vec<(i32, i32, Vec<u8>)>>())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(width, height, argb)| Pixmap {
+                width,
+                height,
+                argb,
+            })
+            .collect();
+
+        let menu = Some(string("Menu")).filter(|menu| !menu.is_empty() && menu != "/");
+
+        Ok(ItemState {
+            title: tooltip.unwrap_or_else(|| string("Title")),
+            status,
+            icon_name: string("IconName"),
+            icon_theme_path: string("IconThemePath"),
+            icon_pixmaps,
+            menu,
+            item_is_menu: properties
+                .get("ItemIsMenu")
+                .and_then(|is_menu| is_menu.get::<bool>())
+                .unwrap_or(false),
+            service,
+        })
+    }
+
+    /// Calls `Activate`, `SecondaryActivate` or `ContextMenu`.
+    // XXX: we don't know where the pointer is, apps placing windows at it get (0, 0)
+    pub async fn activate(&self, item: &ItemState, method: &str) -> anyhow::Result<()> {
+        self.call_item(item, method, &(0i32, 0i32).to_variant())
+            .await
+    }
+
+    pub async fn scroll(&self, item: &ItemState, delta: i32) -> anyhow::Result<()> {
+        self.call_item(item, "Scroll", &(delta, "vertical").to_variant())
+            .await
+    }
+
+    async fn call_item(
+        &self,
+        item: &ItemState,
+        method: &str,
+        params: &Variant,
+    ) -> anyhow::Result<()> {
+        let (bus_name, path) = bus_and_path(&item.service);
+        self.conn
+            .call_future(
+                Some(bus_name),
+                path,
+                ITEM_INTERFACE,
+                method,
+                Some(params),
+                None,
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Top level entries of the item's dbusmenu.
+    pub async fn menu(&self, item: &ItemState) -> anyhow::Result<Vec<MenuItem>> {
+        let menu_path = item.menu.as_ref().ok_or(anyhow!("Tray item has no menu"))?;
+        let bus_name = bus_and_path(&item.service).0;
+
+        // Lets the app update the menu before we show it, not all of them implement it
+        let _ = self
+            .conn
+            .call_future(
+                Some(bus_name),
+                menu_path,
+                MENU_INTERFACE,
+                "AboutToShow",
+                Some(&(0i32,).to_variant()),
+                None,
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+            )
+            .await;
+
+        let layout = self
+            .conn
+            .call_future(
+                Some(bus_name),
+                menu_path,
+                MENU_INTERFACE,
+                "GetLayout",
+                Some(&(0i32, -1i32, Vec::<String>::new()).to_variant()),
+                Some(VariantTy::new("(u(ia{sv}av))")?),
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+            )
+            .await?
+            .child_value(1);
+
+        Ok(parse_menu_item(&layout)
+            .map(|root| root.children)
+            .unwrap_or_default())
+    }
+
+    pub async fn menu_event(&self, item: &ItemState, id: i32) -> anyhow::Result<()> {
+        let menu_path = item.menu.as_ref().ok_or(anyhow!("Tray item has no menu"))?;
+        self.conn
+            .call_future(
+                Some(bus_and_path(&item.service).0),
+                menu_path,
+                MENU_INTERFACE,
+                "Event",
+                Some(&(id, "clicked", 0i32.to_variant(), 0u32).to_variant()),
+                None,
+                gio::DBusCallFlags::NONE,
+                TIMEOUT_MS,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Calls `on_change` whenever an item appears, disappears or changes its icon or status.
+    pub fn watch(&self, on_change: impl Fn() + Clone + 'static) {
+        let on_items_change = on_change.clone();
+        self.conn.signal_subscribe(
+            None,
+            Some(WATCHER_INTERFACE),
+            None,
+            Some(WATCHER_PATH),
+            None,
+            gio::DBusSignalFlags::NONE,
+            move |_, _, _, _, _, _| on_items_change(),
+        );
+
+        self.conn.signal_subscribe(
+            None,
+            Some(ITEM_INTERFACE),
+            None,
+            None,
+            None,
+            gio::DBusSignalFlags::NONE,
+            move |_, _, _, _, _, _| on_change(),
+        );
+    }
+}
+
+fn parse_menu_item(layout: &Variant) -> Option<MenuItem> {
+    // Children come wrapped in variants, so nothing guarantees their type
+    if !layout.is_type(VariantTy::new("(ia{sv}av)").ok()?) {
+        return None;
+    }
+
+    let id = layout.child_value(0).get::<i32>()?;
+    let properties: HashMap<String, Variant> = layout.child_value(1).get()?;
+    let string = |name: &str| {
+        properties
+            .get(name)
+            .and_then(|value| value.str().map(String::from))
+    };
+    let boolean = |name: &str| properties.get(name).and_then(|value| value.get::<bool>());
+
+    if !boolean("visible").unwrap_or(true) {
+        return None;
+    }
+
+    let toggled = string("toggle-type").map(|_| {
+        properties
+            .get("toggle-state")
+            .and_then(|state| state.get::<i32>())
+            == Some(1)
+    });
+
+    let children = layout
+        .child_value(2)
+        .iter()
+        .filter_map(|child| child.as_variant())
+        .filter_map(|child| parse_menu_item(&child))
+        .collect();
+
+    Some(MenuItem {
+        id,
+        label: string("label").unwrap_or_default(),
+        enabled: boolean("enabled").unwrap_or(true),
+        separator: string("type").as_deref() == Some("separator"),
+        toggled,
+        children,
+    })
+}
@@ -0,0 +1,111 @@
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{UnixListener, UnixStream as AsyncUnixStream};
+
+use crate::{xdg, TimerAction};
+
+/// How long a client may take to send a line, `krowbar timer` sends its command right away.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Commands other processes (e.g. `krowbar timer toggle` bound in sxhkd) send to the running bar.
+#[derive(Clone, Debug)]
+pub enum IpcCommand {
+    Timer(TimerAction),
+}
+
+impl IpcCommand {
+    fn encode(&self) -> String {
+        match self {
+            IpcCommand::Timer(action) => format!("timer {}", value_name(action)),
+        }
+    }
+
+    fn decode(line: &str) -> anyhow::Result<IpcCommand> {
+        match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            ["timer", action] => Ok(IpcCommand::Timer(
+                TimerAction::from_str(action, true).map_err(|err| anyhow!(err))?,
+            )),
+            _ => Err(anyhow!("Unknown IPC command: {line}")),
+        }
+    }
+}
+
+fn value_name(value: &impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+pub fn socket_path() -> PathBuf {
+    xdg::runtime_file("sock")
+}
+
+pub fn send(command: IpcCommand) -> anyhow::Result<()> {
+    let path = socket_path();
+    let mut stream = UnixStream::connect(&path).map_err(|err| {
+        anyhow!(
+            "Failed to connect to krowbar at {:?}, is it running? {err}",
+            path
+        )
+    })?;
+
+    writeln!(stream, "{}", command.encode())?;
+    Ok(())
+}
+
+pub async fn listen(sender: async_channel::Sender<IpcCommand>) -> anyhow::Result<()> {
+    let path = socket_path();
+    if std::fs::exists(&path)? {
+        // Started once the instance lock is ours, still, a socket someone listens on isn't
+        // ours to take over
+        if UnixStream::connect(&path).is_ok() {
+            return Err(anyhow!("{:?} is in use by another krowbar", path));
+        }
+        // Leftover from an instance that did not exit cleanly
+        std::fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    log::info!("listening for IPC commands at {:?}", path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        // Handled on their own, a client that never finishes its line must not hold up others
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_client(stream, &sender).await {
+                log::error!("Failed while handling IPC client {:?}", err);
+            }
+        });
+    }
+}
+
+/// Called on exit, so `krowbar timer` doesn't connect to a dead socket.
+pub fn remove_socket() {
+    if let Err(err) = std::fs::remove_file(socket_path()) {
+        log::error!("Failed to remove the IPC socket {:?}", err);
+    }
+}
+
+async fn handle_client(
+    stream: AsyncUnixStream,
+    sender: &async_channel::Sender<IpcCommand>,
+) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(stream).lines();
+
+    while let Some(line) = tokio::time::timeout(CLIENT_TIMEOUT, lines.next_line()).await?? {
+        match IpcCommand::decode(&line) {
+            Ok(command) => sender.send(command).await?,
+            Err(err) => log::error!("{err}"),
+        }
+    }
+
+    Ok(())
+}
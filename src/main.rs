@@ -4,9 +4,11 @@ mod bspwm;
 mod widgets;
 mod xbackend;
 mod config;
+mod ipc;
+mod xdg;
+mod autohide;
 
-use anyhow::anyhow;
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use log::LevelFilter;
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Root};
@@ -21,17 +23,56 @@ pub enum Widget {
     FocusedName,
     Network,
     Cpu,
+    Load,
     Mem,
     Disk,
+    DiskIo,
     Bat,
     Clock,
-    Volume
+    Volume,
+    Timer,
+    Mpris,
+    Tray,
+    Sni,
+    Notifications,
+    Keyboard,
+    Backlight,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    #[command(about = "Control the timer widget of the running krowbar")]
+    Timer {
+        #[arg(value_enum)]
+        action: TimerAction,
+    },
+    #[command(about = "Check the config for unknown keys and invalid values")]
+    CheckConfig {
+        #[arg(
+            help = "Path to config. Defaults to --config or $XDG_CONFIG_HOME/krowbar/config.toml"
+        )]
+        path: Option<PathBuf>,
+    },
+    #[command(about = "Print the default config")]
+    PrintDefaultConfig,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum, Eq, PartialEq)]
+pub enum TimerAction {
+    Start,
+    Pause,
+    Toggle,
+    Reset,
 }
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    #[arg(short, long, help = "Enable debug logging")]
+    #[arg(
+        short,
+        long,
+        help = "Enable debug logging, to $XDG_STATE_HOME/krowbar/krowbar.log"
+    )]
     debug: bool,
     #[arg(long, value_delimiter = ',', help = "Enabled widgets")]
     pub enabled_widgets: Option<Vec<Widget>>,
@@ -43,15 +84,22 @@ pub struct Args {
     pub disabled_widgets: Option<Vec<Widget>>,
     #[arg(
         long,
-        help = "Disable automatic padding. Useful when you want to manage padding yourself."
+        help = "Don't reserve space for the bar. Useful when you want to manage padding yourself."
     )]
     pub no_pad: bool,
+    #[arg(
+        long,
+        help = "Replace the running krowbar, instead of refusing to start"
+    )]
+    pub replace: bool,
     #[arg(
         short,
         long,
-        help = "Path to config. Defaults to ~/.config/krowbar/config.toml"
+        help = "Path to config. Defaults to $XDG_CONFIG_HOME/krowbar/config.toml"
     )]
     pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
 
 #[tokio::main]
@@ -60,16 +108,59 @@ async fn main() -> ExitCode {
     if args.debug {
         setup_logging().expect("Failed to setup debug logging");
     }
+
+    match &args.command {
+        Some(Command::Timer { action }) => {
+            return match ipc::send(ipc::IpcCommand::Timer(*action)) {
+                Ok(_) => ExitCode::SUCCESS,
+                Err(err) => {
+                    eprintln!("{err}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Some(Command::CheckConfig { path }) => {
+            let path = path.clone().unwrap_or(config::path(&args));
+            return match config::check(&path) {
+                Ok(problems) if problems.is_empty() => {
+                    println!("{}: ok", path.display());
+                    ExitCode::SUCCESS
+                }
+                Ok(problems) => {
+                    problems.iter().for_each(|problem| eprintln!("{problem}"));
+                    ExitCode::FAILURE
+                }
+                Err(err) => {
+                    eprintln!("{err}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Some(Command::PrintDefaultConfig) => {
+            return match config::default_toml() {
+                Ok(defaults) => {
+                    print!("{defaults}");
+                    ExitCode::SUCCESS
+                }
+                Err(err) => {
+                    eprintln!("{err}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        None => {}
+    }
+
     let config = config::read(&args).expect("Failed to read config");
 
-    ExitCode::from(bar::run(args, config) as u8)
+    let code = bar::run(args, config);
+    // XXX: The X11 event thread blocks on the connection for good, and the runtime would wait
+    // for it on drop, so exit right away
+    std::process::exit(code)
 }
 
 fn setup_logging() -> anyhow::Result<()> {
-    #[allow(deprecated)] // XXX: Warning regarding Windows, we don't care
-    let log_path = std::env::home_dir()
-        .ok_or(anyhow!("Failed to get home dir"))?
-        .join("krowbar.log");
+    let log_path = xdg::state_dir().join("krowbar.log");
 
     let logfile = FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{d} {l} {m}{n}")))
@@ -1,20 +1,42 @@
 use alsa::Mixer;
+use anyhow::anyhow;
 use battery::Battery;
 use gtk::prelude::ButtonExt;
 use gtk::prelude::*;
 use gtk4 as gtk;
-use std::{sync::Arc, time::Duration};
+use std::{
+    cell::Cell,
+    collections::{HashSet, VecDeque},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use chrono::Local;
+use gtk::gdk;
+use gtk::gio;
+use gtk::glib::{DateTime, TimeZone};
 use gtk::{Button, Label};
-use sysinfo::{Disks, Networks, System};
+use serde::Serialize;
+use sysinfo::{Components, Disks, LoadAvg, Networks, System};
+use tinytemplate::TinyTemplate;
 use xbackend::X11Backend;
 
 use crate::{
-    bar::{EMPTY_DESKTOP, FOCUSED_DESKTOP, NON_EMPTY_DESKTOP, URGENT_DESKTOP},
+    bar::{add_separator, EMPTY_DESKTOP, FOCUSED_DESKTOP, NON_EMPTY_DESKTOP, URGENT_DESKTOP},
     bspwm::MonitorState,
-    instruments::{self},
-    xbackend::{self},
+    config::{self, CpuMode, KrowbarConfig, TimezoneMode, TrayPosition},
+    instruments::{
+        self,
+        backlight::BacklightDevice,
+        diskstats::DiskCounters,
+        dunst::DunstClient,
+        ical::CalendarEvent,
+        mpris::{MprisClient, PlaybackStatus, PlayerState},
+        pomodoro::{Phase, Pomodoro},
+        sni::{ItemState, ItemStatus, MenuItem, Pixmap, SniClient},
+    },
+    xbackend::{self, KeyboardState, Monitor, TrayEvent},
 };
 
 pub struct DesktopButtons {
@@ -110,72 +132,165 @@ impl ActiveNode {
 }
 
 pub struct Storage {
-    pub button: gtk::Button,
+    pub container: gtk::Box,
+    buttons: Vec<gtk::Button>,
+    cfg: config::Storage,
+    /// Configured mounts which are currently mounted, one button each.
+    mounts: Vec<config::Mount>,
+}
+
+struct MountUsage {
+    used_percentage: f32,
+    free_bytes: u64,
 }
 
 impl Storage {
-    pub fn new(disks: &mut Disks) -> Self {
-        let usage = Self::get_used_percentage(disks);
-        let button = Button::builder()
-            .css_name("storage")
-            .label(Self::format(usage))
+    const GIB: f64 = 1024. * 1024. * 1024.;
+
+    pub fn new(disks: &mut Disks, cfg: &config::Storage) -> Self {
+        let container = gtk::Box::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .visible(false)
             .build();
 
-        let mut storage = Storage { button };
-        storage.set_css(usage);
+        let mut storage = Storage {
+            container,
+            buttons: vec![],
+            cfg: cfg.clone(),
+            mounts: vec![],
+        };
+        storage.refresh(disks);
         storage
     }
 
-    fn set_css(&mut self, usage: f32) {
-        if usage > 95. {
-            self.button.set_css_classes(&["storage-high"]);
-        } else if usage > 85. {
-            self.button.set_css_classes(&["storage-mid"]);
+    fn choose_css_class(mount: &config::Mount, usage: f32) -> &str {
+        if usage > mount.alert {
+            "storage-high"
+        } else if usage > mount.warn {
+            "storage-mid"
         } else {
-            self.button.set_css_classes(&["storage-low"]);
+            "storage-low"
         }
     }
 
     pub fn refresh(&mut self, disks: &mut Disks) {
-        let usage = Self::get_used_percentage(disks);
-        let label = Self::format(usage);
+        disks.refresh_list();
 
-        self.button.set_label(&label);
-        self.set_css(usage);
+        // Mounts come and go, e.g. removable drives or network shares
+        let mounts: Vec<config::Mount> = self
+            .cfg
+            .mounts
+            .iter()
+            .filter(|mount| Self::find_usage(disks, &self.cfg.ignored_fs_types, mount).is_some())
+            .cloned()
+            .collect();
+        let paths = |mounts: &[config::Mount]| -> Vec<String> {
+            mounts.iter().map(|mount| mount.path.clone()).collect()
+        };
+        if paths(&mounts) != paths(&self.mounts) {
+            self.rebuild(mounts);
+        }
+
+        self.update(disks);
     }
 
-    fn get_used_percentage(disks: &mut Disks) -> f32 {
-        disks.refresh();
-        let mut total_space = 0;
-        let mut total_available = 0;
-        for disk in disks {
-            total_space += disk.total_space();
-            total_available += disk.available_space();
+    fn rebuild(&mut self, mounts: Vec<config::Mount>) {
+        while let Some(child) = self.container.first_child() {
+            self.container.remove(&child);
         }
-        let total_used = total_space - total_available;
 
-        total_used as f32 / total_space as f32 * 100.0
+        self.buttons = mounts
+            .iter()
+            .enumerate()
+            .map(|(idx, _)| {
+                if idx > 0 {
+                    add_separator(&self.container);
+                }
+                let button = Button::builder().css_name("storage").build();
+                self.container.append(&button);
+                button
+            })
+            .collect();
+
+        self.container.set_visible(!mounts.is_empty());
+        self.mounts = mounts;
+    }
+
+    fn update(&self, disks: &Disks) {
+        for (mount, button) in self.mounts.iter().zip(self.buttons.iter()) {
+            match Self::find_usage(disks, &self.cfg.ignored_fs_types, mount) {
+                Some(usage) => {
+                    button.set_label(&Self::format(mount, &usage));
+                    button.set_css_classes(&[Self::choose_css_class(mount, usage.used_percentage)]);
+                }
+                None => button.set_label(&format!("{} ???", mount.label)),
+            }
+        }
     }
 
-    fn format(usage: f32) -> String {
-        format!("DSK {:.0}%", usage)
+    fn find_usage(
+        disks: &Disks,
+        ignored_fs_types: &[String],
+        mount: &config::Mount,
+    ) -> Option<MountUsage> {
+        disks
+            .list()
+            .iter()
+            .filter(|disk| {
+                let fs = disk.file_system().to_string_lossy();
+                !ignored_fs_types.iter().any(|ignored| *ignored == fs)
+            })
+            .find(|disk| disk.mount_point() == Path::new(&mount.path))
+            .filter(|disk| disk.total_space() > 0)
+            .map(|disk| {
+                let total_space = disk.total_space();
+                let total_used = total_space.saturating_sub(disk.available_space());
+
+                MountUsage {
+                    used_percentage: total_used as f32 / total_space as f32 * 100.0,
+                    free_bytes: disk.available_space(),
+                }
+            })
+    }
+
+    fn format(mount: &config::Mount, usage: &MountUsage) -> String {
+        if mount.show_free {
+            format!(
+                "{} {:.0}% ({:.1} GiB free)",
+                mount.label,
+                usage.used_percentage,
+                usage.free_bytes as f64 / Self::GIB
+            )
+        } else {
+            format!("{} {:.0}%", mount.label, usage.used_percentage)
+        }
     }
 }
 
 pub struct Cpu {
     pub button: gtk::Button,
+    pub temp: Option<Label>,
+    cfg: config::Cpu,
+    history: VecDeque<f32>,
 }
 
 impl Cpu {
-    pub fn new(sys: &mut System) -> Self {
-        let usage = Self::get_used_percentage(sys);
-        let button = Button::builder()
-            .css_name("cpu")
-            .label(Self::format(usage))
-            .build();
+    // Labels used by coretemp (Intel) and k10temp (AMD) for the whole package
+    const PACKAGE_SENSORS: [&str; 3] = ["Package id 0", "Tctl", "Tdie"];
+
+    pub fn new(sys: &mut System, components: &mut Components, cfg: &config::Cpu) -> Self {
+        let button = Button::builder().css_name("cpu").build();
+        let temp = cfg
+            .show_temp
+            .then(|| Label::builder().css_name("cpu-temp").build());
 
-        let mut cpu = Cpu { button };
-        cpu.set_css(usage);
+        let mut cpu = Cpu {
+            button,
+            temp,
+            cfg: cfg.clone(),
+            history: VecDeque::with_capacity(cfg.history),
+        };
+        cpu.refresh(sys, components);
         cpu
     }
 
@@ -189,11 +304,36 @@ impl Cpu {
         }
     }
 
-    pub fn refresh(&mut self, sys: &mut System) {
-        let usage = &Self::get_used_percentage(sys);
+    fn set_temp_css(&self, temp_label: &Label, temp: f32) {
+        if temp > self.cfg.temp_alert {
+            temp_label.set_css_classes(&["cpu-temp-high"]);
+        } else if temp > self.cfg.temp_warn {
+            temp_label.set_css_classes(&["cpu-temp-mid"]);
+        } else {
+            temp_label.set_css_classes(&["cpu-temp-low"]);
+        }
+    }
 
-        self.set_css(*usage);
-        self.button.set_label(&Self::format(*usage))
+    pub fn refresh(&mut self, sys: &mut System, components: &mut Components) {
+        let usage = Self::get_used_percentage(sys);
+
+        if self.history.len() >= self.cfg.history {
+            self.history.pop_front();
+        }
+        self.history.push_back(usage);
+
+        self.set_css(usage);
+        self.button.set_label(&self.format(sys, usage));
+
+        if let Some(temp_label) = &self.temp {
+            match self.get_package_temp(components) {
+                Some(temp) => {
+                    temp_label.set_label(&format!("{:.0}°C", temp));
+                    self.set_temp_css(temp_label, temp);
+                }
+                None => temp_label.set_label("???"),
+            }
+        }
     }
 
     fn get_used_percentage(sys: &mut System) -> f32 {
@@ -201,26 +341,143 @@ impl Cpu {
         sys.global_cpu_usage()
     }
 
-    fn format(usage: f32) -> String {
-        format!("CPU {:.0}%", usage)
+    fn get_package_temp(&self, components: &mut Components) -> Option<f32> {
+        components.refresh();
+        components
+            .list()
+            .iter()
+            .find(|component| match &self.cfg.temp_sensor {
+                Some(sensor) => component.label().contains(sensor.as_str()),
+                None => Self::PACKAGE_SENSORS
+                    .iter()
+                    .any(|sensor| component.label().contains(sensor)),
+            })
+            .map(|component| component.temperature())
+            .filter(|temp| !temp.is_nan())
+    }
+
+    fn format(&self, sys: &System, usage: f32) -> String {
+        match self.cfg.mode {
+            CpuMode::Percent => format!("CPU {:.0}%", usage),
+            CpuMode::Graph => {
+                let history: Vec<f32> = self.history.iter().copied().collect();
+                format!("CPU {} {:.0}%", sparkline(&history, 100.), usage)
+            }
+            CpuMode::Cores => {
+                let cores: Vec<f32> = sys.cpus().iter().map(|cpu| cpu.cpu_usage()).collect();
+                format!("CPU {}", sparkline(&cores, 100.))
+            }
+        }
+    }
+}
+
+/// Renders values as a row of unicode blocks, scaled against `max`.
+pub fn sparkline(values: &[f32], max: f32) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    values
+        .iter()
+        .map(|value| {
+            let ratio = if max > 0. {
+                (value / max).clamp(0., 1.)
+            } else {
+                0.
+            };
+            BLOCKS[(ratio * (BLOCKS.len() - 1) as f32).round() as usize]
+        })
+        .collect()
+}
+
+pub struct Load {
+    pub button: gtk::Button,
+    cfg: config::Load,
+    core_count: usize,
+}
+
+impl Load {
+    pub fn new(sys: &System, cfg: &config::Load) -> Self {
+        let button = Button::builder().css_name("load").build();
+
+        let mut load = Load {
+            button,
+            cfg: cfg.clone(),
+            core_count: sys.cpus().len().max(1),
+        };
+        load.refresh();
+        load
+    }
+
+    fn set_css(&mut self, per_core: f64) {
+        if per_core > self.cfg.alert {
+            self.button.set_css_classes(&["load-high"]);
+        } else if per_core > self.cfg.warn {
+            self.button.set_css_classes(&["load-mid"]);
+        } else {
+            self.button.set_css_classes(&["load-low"]);
+        }
+    }
+
+    pub fn refresh(&mut self) {
+        let LoadAvg { one, five, fifteen } = System::load_average();
+        let cores = self.core_count as f64;
+
+        // Thresholds are always compared against the per core 1 minute load
+        self.set_css(one / cores);
+
+        let (one, five, fifteen) = if self.cfg.normalize {
+            (one / cores, five / cores, fifteen / cores)
+        } else {
+            (one, five, fifteen)
+        };
+
+        let mut label = format!("LOAD {:.2} {:.2} {:.2}", one, five, fifteen);
+        if self.cfg.show_uptime {
+            label.push_str(&format!(" UP {}", Self::format_uptime(System::uptime())));
+        }
+
+        self.button.set_label(&label);
+    }
+
+    fn format_uptime(secs: u64) -> String {
+        let days = secs / 86400;
+        let hours = (secs / 3600) % 24;
+        let minutes = (secs / 60) % 60;
+
+        if days > 0 {
+            format!("{days}d {:0>2}:{:0>2}", hours, minutes)
+        } else {
+            format!("{:0>2}:{:0>2}", hours, minutes)
+        }
     }
 }
 
 pub struct Mem {
     pub button: gtk::Button,
+    cfg: config::Mem,
+}
+
+#[derive(Serialize)]
+struct MemStats {
+    used: String,
+    available: String,
+    total: String,
+    percent: String,
+    swap_used: String,
+    swap_total: String,
+    swap_percent: String,
 }
 
 impl Mem {
-    pub fn new(sys: &mut System) -> Self {
-        sys.refresh_memory();
-        let usage = Self::get_used_percentage(sys);
-        let button = Button::builder()
-            .css_name("mem")
-            .label(Self::format(usage))
-            .build();
+    const GIB: f64 = 1024. * 1024. * 1024.;
 
-        let mut mem = Mem { button };
-        mem.set_css(usage);
+    pub fn new(sys: &mut System, cfg: &config::Mem) -> Self {
+        let button = Button::builder().css_name("mem").build();
+
+        let mut mem = Mem {
+            button,
+            cfg: cfg.clone(),
+        };
+        mem.refresh(sys);
         mem
     }
 
@@ -235,20 +492,42 @@ impl Mem {
     }
 
     pub fn refresh(&mut self, sys: &mut System) {
-        let usage = Self::get_used_percentage(sys);
-        let label = Self::format(usage);
+        sys.refresh_memory();
+        let usage = Self::percentage(sys.used_memory(), sys.total_memory());
+        let label = self.format(sys, usage).unwrap_or_else(|err| {
+            log::error!("Failed to render mem format {err}");
+            String::from("???")
+        });
 
         self.button.set_label(&label);
         self.set_css(usage);
     }
 
-    fn get_used_percentage(sys: &mut System) -> f32 {
-        sys.refresh_memory();
-        sys.used_memory() as f32 / sys.total_memory() as f32 * 100.0
+    fn percentage(used: u64, total: u64) -> f32 {
+        if total == 0 {
+            0.
+        } else {
+            used as f32 / total as f32 * 100.0
+        }
     }
 
-    fn format(usage: f32) -> String {
-        format!("MEM {:.0}%", usage)
+    fn format(&self, sys: &System, usage: f32) -> anyhow::Result<String> {
+        let gib = |bytes: u64| format!("{:.1}", bytes as f64 / Self::GIB);
+        let stats = MemStats {
+            used: gib(sys.used_memory()),
+            available: gib(sys.available_memory()),
+            total: gib(sys.total_memory()),
+            percent: format!("{:.0}", usage),
+            swap_used: gib(sys.used_swap()),
+            swap_total: gib(sys.total_swap()),
+            swap_percent: format!("{:.0}", Self::percentage(sys.used_swap(), sys.total_swap())),
+        };
+
+        let mut tt = TinyTemplate::new();
+        tt.set_default_formatter(&tinytemplate::format_unescaped);
+        tt.add_template("mem", &self.cfg.format)?;
+
+        Ok(tt.render("mem", &stats)?)
     }
 }
 
@@ -256,41 +535,487 @@ pub struct Clock {
     short: bool,
     pub button: Button,
     pub cal: gtk::Calendar,
+    pub popup: gtk::Window,
+    cfg: config::Clock,
+    extra_timezones: Vec<TimeZone>,
+    // 0 is the local timezone, the rest index into `extra_timezones`
+    timezone_idx: usize,
+    events: Rc<Mutex<Vec<CalendarEvent>>>,
+    calendar_monitors: Vec<gio::FileMonitor>,
+    closed_at: Option<Instant>,
 }
 
 impl Clock {
-    pub fn new() -> Self {
-        let button = Button::builder()
-            .css_name("clock")
-            .label(format!("{}", Local::now().format("%H:%M")))
+    const REOPEN_GUARD: Duration = Duration::from_millis(300);
+
+    pub fn new(cfg: &config::Clock) -> Self {
+        let button = Button::builder().css_name("clock").build();
+
+        let cal = gtk::Calendar::builder().show_week_numbers(true).build();
+
+        let cal_scroll = gtk::EventControllerScroll::new(gtk::EventControllerScrollFlags::VERTICAL);
+        cal_scroll.connect_scroll(|controller, _, dy| {
+            if let Some(cal) = controller.widget().and_downcast::<gtk::Calendar>() {
+                let months = if dy > 0. { 1 } else { -1 };
+                if let Ok(date) = cal.date().add_months(months) {
+                    cal.select_day(&date);
+                }
+            }
+            gtk::glib::Propagation::Stop
+        });
+        cal.add_controller(cal_scroll);
+
+        let events = Rc::new(Mutex::new(Self::read_events(cfg)));
+        let month_events = events.clone();
+        cal.connect_month_notify(move |cal| {
+            Self::mark_event_days(cal, &month_events.lock().expect("events mutex"));
+        });
+
+        let popup = gtk::Window::builder()
+            .title("krowbar-calendar")
+            .css_name("calendar-popup")
+            .decorated(false)
+            .resizable(false)
+            .child(&cal)
             .build();
 
-        let cal = gtk::Calendar::builder().visible(false).build();
+        let escape = gtk::EventControllerKey::new();
+        escape.connect_key_pressed(|controller, key, _, _| {
+            if key == gdk::Key::Escape {
+                if let Some(popup) = controller.widget() {
+                    popup.set_visible(false);
+                }
+                gtk::glib::Propagation::Stop
+            } else {
+                gtk::glib::Propagation::Proceed
+            }
+        });
+        popup.add_controller(escape);
+
+        // Clicking anywhere outside moves the focus away from the popup
+        popup.connect_is_active_notify(|popup| {
+            if !popup.is_active() {
+                popup.set_visible(false);
+            }
+        });
+
+        let extra_timezones = cfg
+            .timezones
+            .iter()
+            .filter_map(|identifier| {
+                // XXX: glib silently falls back to UTC on unknown identifiers
+                let tz = TimeZone::new(Some(identifier));
+                if tz.identifier() == "UTC" && identifier != "UTC" {
+                    log::error!("Unknown timezone {identifier}, ignoring");
+                    None
+                } else {
+                    Some(tz)
+                }
+            })
+            .collect();
 
-        Clock {
+        let clock = Clock {
             short: true,
             button,
             cal,
-        }
+            popup,
+            cfg: cfg.clone(),
+            extra_timezones,
+            timezone_idx: 0,
+            events,
+            calendar_monitors: vec![],
+            closed_at: None,
+        };
+        clock.refresh();
+        clock
     }
 
-    pub fn toggle_clock(&mut self) {
+    pub fn toggle_clock(
+        &mut self,
+        x11: &X11Backend,
+        monitor: &Monitor,
+        cfg: &KrowbarConfig,
+    ) -> anyhow::Result<()> {
+        // Pressing the clock while the popup is open takes the focus away from the popup, which
+        // closes it. The release of that same click must not open it again.
+        if self.short
+            && self
+                .closed_at
+                .is_some_and(|closed_at| closed_at.elapsed() < Self::REOPEN_GUARD)
+        {
+            return Ok(());
+        }
+
         self.short = !self.short;
-        self.cal.set_visible(!self.short);
+        self.refresh();
+
+        if self.short {
+            self.popup.set_visible(false);
+            Ok(())
+        } else {
+            self.open_popup(x11, monitor, cfg)
+        }
+    }
+
+    /// Calls `on_change` whenever something changes in the configured calendar directories.
+    pub fn watch_calendars(&mut self, on_change: impl Fn() + Clone + 'static) {
+        let dirs = instruments::ical::calendar_dirs(&Self::configured_dirs(&self.cfg));
+
+        self.calendar_monitors = dirs
+            .iter()
+            .filter_map(|dir| {
+                gio::File::for_path(dir)
+                    .monitor_directory(gio::FileMonitorFlags::NONE, None::<&gio::Cancellable>)
+                    .inspect_err(|err| log::error!("Failed to watch calendar dir {:?} {err}", dir))
+                    .ok()
+            })
+            .inspect(|monitor| {
+                let on_change = on_change.clone();
+                monitor.connect_changed(move |_, _, _, event| {
+                    if event != gio::FileMonitorEvent::AttributeChanged {
+                        on_change()
+                    }
+                });
+            })
+            .collect();
+    }
+
+    pub fn reload_events(&mut self) {
+        let events = Self::read_events(&self.cfg);
+        Self::mark_event_days(&self.cal, &events);
+        *self.events.lock().expect("events mutex") = events;
+        self.refresh();
+    }
+
+    fn read_events(cfg: &config::Clock) -> Vec<CalendarEvent> {
+        instruments::ical::read_events(&Self::configured_dirs(cfg))
+    }
+
+    fn configured_dirs(cfg: &config::Clock) -> Vec<PathBuf> {
+        cfg.calendar_dirs
+            .iter()
+            .map(|dir| config::expand_home(dir))
+            .collect()
+    }
+
+    fn mark_event_days(cal: &gtk::Calendar, events: &[CalendarEvent]) {
+        cal.clear_marks();
+        // GTK counts months from 0
+        let (year, month) = (cal.year(), cal.month() + 1);
+        for event in events {
+            let (event_year, event_month, event_day) = event.start.ymd();
+            if event_year == year && event_month == month {
+                cal.mark_day(event_day as u32);
+            }
+        }
+    }
+
+    fn next_event(&self) -> Option<String> {
+        let now = DateTime::now_local().ok()?;
+
+        self.events
+            .lock()
+            .expect("events mutex")
+            .iter()
+            .find(|event| {
+                if event.all_day {
+                    event.start.ymd() >= now.ymd()
+                } else {
+                    event.start >= now
+                }
+            })
+            .and_then(|event| {
+                let format = if event.all_day { "%a" } else { "%a %H:%M" };
+                let start = event.start.format(format).ok()?;
+                Some(format!("{start} {}", event.summary))
+            })
+    }
+
+    pub fn close(&mut self) {
+        if !self.short {
+            self.closed_at = Some(Instant::now());
+        }
+        self.short = true;
+        self.popup.set_visible(false);
         self.refresh()
     }
 
+    fn open_popup(
+        &self,
+        x11: &X11Backend,
+        monitor: &Monitor,
+        cfg: &KrowbarConfig,
+    ) -> anyhow::Result<()> {
+        // Start from the current month, even if the previous popup was scrolled away
+        if let Ok(today) = DateTime::now_local() {
+            self.cal.select_day(&today);
+        }
+        Self::mark_event_days(&self.cal, &self.events.lock().expect("events mutex"));
+
+        if !self.popup.is_realized() {
+            WidgetExt::realize(&self.popup);
+            x11.set_as_popup(Self::popup_xid(&self.popup)?)?;
+        }
+
+        let (_, width, _, _) = self.popup.measure(gtk::Orientation::Horizontal, -1);
+        let (_, height, _, _) = self.popup.measure(gtk::Orientation::Vertical, width);
+        let anchor_right = self
+            .button
+            .root()
+            .and_then(|root| self.button.compute_bounds(&root))
+            .map(|bounds| (bounds.x() + bounds.width()) as i32)
+            .unwrap_or(0);
+
+        self.popup.set_visible(true);
+        // Make sure the popup is mapped before focusing it from our own X11 connection
+        WidgetExt::display(&self.popup).sync();
+
+        let popup_win = Self::popup_xid(&self.popup)?;
+        x11.place_popup(
+            popup_win,
+            monitor,
+            cfg,
+            anchor_right,
+            width as u16,
+            height as u16,
+        )?;
+        x11.raise_and_focus(popup_win)?;
+
+        Ok(())
+    }
+
+    fn popup_xid(popup: &gtk::Window) -> anyhow::Result<u32> {
+        Ok(popup
+            .surface()
+            .ok_or(anyhow!("No surface on calendar popup!"))?
+            .downcast::<gdk4_x11::X11Surface>()
+            .map_err(|_| anyhow!("Failed to cast GTK surface to X11 surface"))?
+            .xid() as u32)
+    }
+
+    pub fn cycle_timezone(&mut self, forward: bool) {
+        if let TimezoneMode::Cycle = self.cfg.timezone_mode {
+            let count = self.extra_timezones.len() + 1;
+            self.timezone_idx = if forward {
+                (self.timezone_idx + 1) % count
+            } else {
+                (self.timezone_idx + count - 1) % count
+            };
+            self.refresh()
+        }
+    }
+
     pub fn refresh(&self) {
         self.button.set_label(&Self::clock_now(self))
     }
 
     fn clock_now(&self) -> String {
-        if self.short {
-            format!("{}", Local::now().format("%H:%M"))
+        let format = if self.short {
+            &self.cfg.format
         } else {
-            format!("{}", Local::now().format("%Y-%m-%d, %A @ %H:%M:%S"))
+            &self.cfg.format_long
+        };
+
+        let time = self.time_now(format);
+        match self.next_event() {
+            Some(event) if !self.short => format!("{time} | {event}"),
+            _ => time,
         }
     }
+
+    fn time_now(&self, format: &str) -> String {
+        match self.cfg.timezone_mode {
+            TimezoneMode::Cycle => match self.timezone_idx {
+                0 => Self::format_time(&TimeZone::local(), format, false),
+                idx => Self::format_time(&self.extra_timezones[idx - 1], format, true),
+            },
+            TimezoneMode::SideBySide => {
+                std::iter::once(Self::format_time(&TimeZone::local(), format, false))
+                    .chain(
+                        self.extra_timezones
+                            .iter()
+                            .map(|tz| Self::format_time(tz, format, true)),
+                    )
+                    .collect::<Vec<String>>()
+                    .join(" | ")
+            }
+        }
+    }
+
+    // glib formatting respects the locale, so weekday and month names get translated
+    fn format_time(tz: &TimeZone, format: &str, with_abbreviation: bool) -> String {
+        let formatted = DateTime::now(tz).and_then(|now| {
+            let time = now.format(format)?;
+            if with_abbreviation {
+                Ok(format!("{} {}", now.timezone_abbreviation(), time))
+            } else {
+                Ok(time.to_string())
+            }
+        });
+
+        formatted.unwrap_or_else(|err| {
+            log::error!("Failed to format clock {err}");
+            String::from("???")
+        })
+    }
+}
+
+pub struct Timer {
+    pub button: Button,
+}
+
+impl Timer {
+    pub fn new(pomodoro: &mut Pomodoro) -> Self {
+        let button = Button::builder().css_name("timer").build();
+
+        let timer = Timer { button };
+        timer.refresh(pomodoro);
+        timer
+    }
+
+    pub fn refresh(&self, pomodoro: &mut Pomodoro) {
+        pomodoro.update();
+
+        let remaining = pomodoro.remaining().as_secs();
+        let time = format!("{:0>2}:{:0>2}", remaining / 60, remaining % 60);
+        let phase_class = match pomodoro.phase() {
+            Phase::Work => "timer-work",
+            Phase::Break | Phase::LongBreak => "timer-break",
+        };
+
+        if pomodoro.is_idle() {
+            self.button.set_label(&format!("POMO {time}"));
+            self.button.set_css_classes(&["timer-idle"]);
+        } else {
+            let label = match pomodoro.phase() {
+                Phase::Work => "WORK",
+                Phase::Break | Phase::LongBreak => "BREAK",
+            };
+            self.button.set_label(&format!("{label} {time}"));
+
+            if pomodoro.is_running() {
+                self.button.set_css_classes(&[phase_class]);
+            } else {
+                self.button.set_css_classes(&[phase_class, "timer-paused"]);
+            }
+        }
+    }
+}
+
+pub struct Mpris {
+    pub button: Button,
+    client: Rc<MprisClient>,
+    cfg: config::Mpris,
+    player: Option<PlayerState>,
+    scroll_offset: usize,
+}
+
+impl Mpris {
+    /// Hidden until the players, shared by all bars, get broadcasted.
+    pub fn new(client: Rc<MprisClient>, cfg: &config::Mpris) -> Self {
+        let button = Button::builder().css_name("mpris").visible(false).build();
+
+        Mpris {
+            button,
+            client,
+            cfg: cfg.clone(),
+            player: None,
+            scroll_offset: 0,
+        }
+    }
+
+    pub fn update_players(&mut self, players: Vec<PlayerState>) {
+        let player = self.choose_player(players);
+        let track = |player: &Option<PlayerState>| {
+            player
+                .as_ref()
+                .map(|p| (p.bus_name.clone(), p.artist.clone(), p.title.clone()))
+        };
+        if track(&player) != track(&self.player) {
+            self.scroll_offset = 0;
+        }
+
+        self.player = player;
+        self.render();
+    }
+
+    /// Prefers playing players, then follows the configured priority. Players missing from
+    /// the config go last.
+    fn choose_player(&self, players: Vec<PlayerState>) -> Option<PlayerState> {
+        let priority = |player: &PlayerState| {
+            self.cfg
+                .players
+                .iter()
+                .position(|name| player.short_name().starts_with(name.as_str()))
+                .unwrap_or(self.cfg.players.len())
+        };
+
+        players
+            .into_iter()
+            .filter(|player| player.status != PlaybackStatus::Stopped)
+            .min_by_key(|player| (player.status != PlaybackStatus::Playing, priority(player)))
+    }
+
+    /// Scrolls long titles by one character.
+    pub fn tick(&mut self) {
+        if self.player.is_some() {
+            self.scroll_offset += 1;
+            self.render();
+        }
+    }
+
+    pub fn call(&self, method: &'static str) {
+        if let Some(player) = &self.player {
+            let (client, bus_name) = (self.client.clone(), player.bus_name.clone());
+            gtk::glib::spawn_future_local(async move {
+                if let Err(err) = client.call(&bus_name, method).await {
+                    log::error!("Failed to call {method} on {bus_name} {err}");
+                }
+            });
+        }
+    }
+
+    fn render(&self) {
+        match &self.player {
+            Some(player) => {
+                let icon = match player.status {
+                    PlaybackStatus::Playing => "▶",
+                    _ => "⏸",
+                };
+                let track = if player.artist.is_empty() {
+                    player.title.clone()
+                } else {
+                    format!("{} - {}", player.artist, player.title)
+                };
+
+                self.button
+                    .set_label(&format!("{icon} {}", self.marquee(&track)));
+                self.button.set_css_classes(&[match player.status {
+                    PlaybackStatus::Playing => "mpris-playing",
+                    _ => "mpris-paused",
+                }]);
+                self.button.set_visible(true);
+            }
+            None => self.button.set_visible(false),
+        }
+    }
+
+    fn marquee(&self, text: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        if chars.len() <= self.cfg.max_length {
+            return text.to_string();
+        }
+
+        let padded: Vec<char> = chars.into_iter().chain("   ".chars()).collect();
+        let offset = self.scroll_offset % padded.len();
+
+        padded
+            .iter()
+            .cycle()
+            .skip(offset)
+            .take(self.cfg.max_length)
+            .collect()
+    }
 }
 
 pub struct Batteries {
@@ -453,6 +1178,106 @@ impl Network {
     }
 }
 
+pub struct DiskIo {
+    pub label: gtk::Label,
+    cfg: config::DiskIo,
+    last_sample: Option<(Instant, DiskCounters)>,
+    read_history: VecDeque<f32>,
+    write_history: VecDeque<f32>,
+}
+
+impl DiskIo {
+    const MIB: f64 = 1024. * 1024.;
+
+    pub fn new(cfg: &config::DiskIo) -> Self {
+        let label = Label::builder().css_name("disk-io").label("IO ???").build();
+
+        let mut disk_io = DiskIo {
+            label,
+            cfg: cfg.clone(),
+            last_sample: None,
+            read_history: VecDeque::with_capacity(cfg.history),
+            write_history: VecDeque::with_capacity(cfg.history),
+        };
+        disk_io.refresh();
+        disk_io
+    }
+
+    fn set_css(&self, bytes_per_sec: f64) {
+        let mib_per_sec = bytes_per_sec / Self::MIB;
+        if mib_per_sec > self.cfg.alert {
+            self.label.set_css_classes(&["disk-io-high"]);
+        } else if mib_per_sec > self.cfg.warn {
+            self.label.set_css_classes(&["disk-io-mid"]);
+        } else {
+            self.label.set_css_classes(&["disk-io-low"]);
+        }
+    }
+
+    pub fn refresh(&mut self) {
+        let counters = match instruments::diskstats::read_counters(&self.cfg.devices) {
+            Ok(counters) => counters,
+            Err(err) => {
+                log::error!("Failed to read disk stats {err}");
+                self.label.set_label("IO ???");
+                return;
+            }
+        };
+        let now = Instant::now();
+
+        if let Some((last_instant, last_counters)) = self.last_sample {
+            let elapsed = now.duration_since(last_instant).as_secs_f64();
+            if elapsed > 0. {
+                let read = counters.read_bytes.saturating_sub(last_counters.read_bytes) as f64
+                    / elapsed;
+                let written = counters
+                    .written_bytes
+                    .saturating_sub(last_counters.written_bytes)
+                    as f64
+                    / elapsed;
+
+                Self::push_history(&mut self.read_history, self.cfg.history, read as f32);
+                Self::push_history(&mut self.write_history, self.cfg.history, written as f32);
+
+                self.label.set_label(&self.format(read, written));
+                self.set_css(read + written);
+            }
+        }
+
+        self.last_sample = Some((now, counters));
+    }
+
+    fn push_history(history: &mut VecDeque<f32>, size: usize, value: f32) {
+        if history.len() >= size {
+            history.pop_front();
+        }
+        history.push_back(value);
+    }
+
+    fn format(&self, read: f64, written: f64) -> String {
+        let read_label = Network::format_size(read as u64);
+        let write_label = Network::format_size(written as u64);
+
+        if self.cfg.show_graph {
+            let graph = |history: &VecDeque<f32>| {
+                let values: Vec<f32> = history.iter().copied().collect();
+                let max = values.iter().copied().fold(0., f32::max);
+                sparkline(&values, max)
+            };
+
+            format!(
+                "IO R {} {}/s W {} {}/s",
+                graph(&self.read_history),
+                read_label,
+                graph(&self.write_history),
+                write_label
+            )
+        } else {
+            format!("IO R {}/s W {}/s", read_label, write_label)
+        }
+    }
+}
+
 pub struct Volume {
     pub label: gtk::Label,
 }
@@ -489,3 +1314,613 @@ impl Volume {
         }
     }
 }
+
+pub struct Backlight {
+    pub label: Label,
+    device: Option<BacklightDevice>,
+    cfg: config::Backlight,
+    brightness_monitor: Option<gio::FileMonitor>,
+}
+
+impl Backlight {
+    pub fn new(cfg: &config::Backlight) -> Self {
+        let label = Label::builder().css_name("backlight").build();
+        let device = BacklightDevice::find(cfg.device.as_deref())
+            .inspect_err(|err| log::info!("Backlight widget disabled {err}"))
+            .ok();
+
+        let backlight = Backlight {
+            label,
+            device,
+            cfg: cfg.clone(),
+            brightness_monitor: None,
+        };
+        backlight.refresh();
+        backlight
+    }
+
+    pub fn watch(&mut self, on_change: impl Fn() + 'static) {
+        let Some(device) = &self.device else {
+            return;
+        };
+
+        self.brightness_monitor = gio::File::for_path(device.brightness_path())
+            .monitor_file(gio::FileMonitorFlags::NONE, None::<&gio::Cancellable>)
+            .inspect_err(|err| log::error!("Failed to watch backlight brightness {err}"))
+            .ok()
+            .inspect(|monitor| {
+                monitor.connect_changed(move |_, _, _, _| on_change());
+            });
+    }
+
+    pub fn refresh(&self) {
+        Self::show(&self.label, self.device.as_ref());
+    }
+
+    fn show(label: &Label, device: Option<&BacklightDevice>) {
+        match Self::percent(device) {
+            Ok(percent) => label.set_label(&format!("BRI {percent:.0}%")),
+            Err(err) => {
+                log::debug!("Failed to read backlight brightness {err}");
+                label.set_label("BRI ???");
+            }
+        }
+    }
+
+    fn percent(device: Option<&BacklightDevice>) -> anyhow::Result<f32> {
+        let device = device.ok_or(anyhow!("No backlight device"))?;
+        let max = device.max_brightness()?;
+        if max == 0 {
+            return Err(anyhow!("Backlight max brightness is 0"));
+        }
+
+        Ok(device.brightness()? as f32 / max as f32 * 100.)
+    }
+
+    /// Changes brightness by the configured step, in percent of the max brightness.
+    pub fn adjust(&self, brighter: bool) {
+        let Some(device) = &self.device else {
+            return;
+        };
+
+        let brightness = device.max_brightness().and_then(|max| {
+            // Some panels report max brightness in the millions, u32 math would overflow
+            let percent = u64::from(self.cfg.step.clamp(1, 100));
+            let step = (u64::from(max) * percent / 100).max(1) as u32;
+            let current = device.brightness()?;
+            let brightness = if brighter {
+                current.saturating_add(step).min(max)
+            } else {
+                // Some panels turn off completely at 0
+                current.saturating_sub(step).max(1)
+            };
+
+            Ok(brightness)
+        });
+        let brightness = match brightness {
+            Ok(brightness) => brightness,
+            Err(err) => {
+                log::error!("Failed to read backlight brightness {err}");
+                return;
+            }
+        };
+
+        // logind may take a while, e.g. asking polkit, the bar must not wait for it
+        let (label, device) = (self.label.clone(), device.clone());
+        gtk::glib::spawn_future_local(async move {
+            if let Err(err) = device.set_brightness(brightness).await {
+                log::error!("Failed to set backlight brightness {err}");
+            }
+            Self::show(&label, Some(&device));
+        });
+    }
+}
+
+pub struct Keyboard {
+    pub button: Button,
+    x11: Arc<X11Backend>,
+    cfg: config::Keyboard,
+}
+
+impl Keyboard {
+    pub fn new(x11: Arc<X11Backend>, cfg: &config::Keyboard) -> Self {
+        let button = Button::builder().css_name("keyboard").build();
+
+        let keyboard = Keyboard {
+            button,
+            x11,
+            cfg: cfg.clone(),
+        };
+        keyboard.refresh();
+        keyboard
+    }
+
+    pub fn refresh(&self) {
+        match self.x11.keyboard_state() {
+            Ok(state) => {
+                self.button.set_label(&self.format(&state));
+                if state.caps_lock {
+                    self.button.set_css_classes(&["keyboard-caps"]);
+                } else {
+                    self.button.set_css_classes(&[]);
+                }
+            }
+            Err(err) => {
+                log::error!("Failed to get keyboard state {:?}", err);
+                self.button.set_label("KBD ???");
+            }
+        }
+    }
+
+    pub fn next_group(&self) {
+        if let Err(err) = self.x11.next_keyboard_group() {
+            log::error!("Failed to switch keyboard group {:?}", err);
+        }
+    }
+
+    fn format(&self, state: &KeyboardState) -> String {
+        let group = state.group as usize;
+        let short_name = state.layouts.get(group).map(|layout| layout.to_uppercase());
+        let long_name = state.group_names.get(group).cloned();
+
+        let mut label = if self.cfg.long_names {
+            long_name.or(short_name)
+        } else {
+            short_name.or(long_name)
+        }
+        .unwrap_or("???".to_string());
+
+        if self.cfg.show_locks {
+            if state.caps_lock {
+                label.push_str(" CAPS");
+            }
+            if state.num_lock {
+                label.push_str(" NUM");
+            }
+        }
+
+        label
+    }
+}
+
+/// dunst do-not-disturb indicator.
+pub struct Notifications {
+    pub button: Button,
+    client: Option<DunstClient>,
+}
+
+impl Notifications {
+    pub fn new() -> Self {
+        let button = Button::builder().css_name("notifications").build();
+        let client = DunstClient::new()
+            .inspect_err(|err| log::error!("Failed to connect to the session bus {err}"))
+            .ok();
+
+        let notifications = Notifications { button, client };
+        notifications.refresh();
+        notifications
+    }
+
+    pub fn watch(&self, on_change: impl Fn() + 'static) {
+        if let Some(client) = &self.client {
+            client.watch(on_change);
+        }
+    }
+
+    pub fn refresh(&self) {
+        let status = self
+            .client
+            .as_ref()
+            .ok_or(anyhow!("No session bus"))
+            .and_then(|client| client.status());
+
+        match status {
+            Ok(status) if status.paused => {
+                if status.waiting > 0 {
+                    self.button.set_label(&format!("DND {}", status.waiting));
+                } else {
+                    self.button.set_label("DND");
+                }
+                self.button.set_css_classes(&["notifications-paused"]);
+            }
+            Ok(_) => {
+                self.button.set_label("NTF");
+                self.button.set_css_classes(&["notifications-active"]);
+            }
+            Err(err) => {
+                // dunst not running is common enough, no need to flood the log
+                log::debug!("Failed to get dunst status {err}");
+                self.button.set_label("NTF ???");
+                self.button.set_css_classes(&[]);
+            }
+        }
+    }
+
+    pub fn toggle(&self) {
+        let Some(client) = &self.client else {
+            return;
+        };
+
+        let result = client
+            .status()
+            .and_then(|status| client.set_paused(!status.paused));
+        if let Err(err) = result {
+            log::error!("Failed to toggle do-not-disturb {err}");
+        }
+        self.refresh();
+    }
+}
+
+/// StatusNotifierItem tray icons, rendered as regular GTK widgets.
+pub struct Sni {
+    pub container: gtk::Box,
+    pub client: Rc<SniClient>,
+    items: Vec<ItemState>,
+    icon_size: i32,
+}
+
+impl Sni {
+    pub fn new(cfg: &KrowbarConfig) -> anyhow::Result<Self> {
+        let container = gtk::Box::builder()
+            .css_name("sni")
+            .orientation(gtk::Orientation::Horizontal)
+            .spacing(2)
+            .visible(false)
+            .build();
+
+        Ok(Sni {
+            container,
+            client: Rc::new(SniClient::new()?),
+            items: vec![],
+            icon_size: cfg
+                .bar
+                .height
+                .saturating_sub(2 * cfg.tray.icon_padding)
+                .max(1) as i32,
+        })
+    }
+
+    /// Shows the items listed by `SniClient::items`.
+    pub fn show(&mut self, items: Vec<ItemState>) {
+        // Items emit lots of signals, most of them change nothing we show
+        if items == self.items {
+            return;
+        }
+        let client = self.client.clone();
+
+        while let Some(child) = self.container.first_child() {
+            self.container.remove(&child);
+        }
+        for item in items
+            .iter()
+            .filter(|item| item.status != ItemStatus::Passive)
+        {
+            self.container.append(&self.item_button(item, &client));
+        }
+
+        self.container
+            .set_visible(self.container.first_child().is_some());
+        self.items = items;
+    }
+
+    fn item_button(&self, item: &ItemState, client: &Rc<SniClient>) -> Button {
+        let button = Button::builder()
+            .css_name("sni-item")
+            .child(&self.icon(item))
+            .tooltip_text(&item.title)
+            .build();
+        if item.status == ItemStatus::NeedsAttention {
+            button.add_css_class("sni-attention");
+        }
+
+        // The calls are async, apps that hang (or take their time) must not freeze the bar
+        let (click_client, click_item) = (client.clone(), item.clone());
+        button.connect_clicked(move |button| {
+            let (client, item, button) = (click_client.clone(), click_item.clone(), button.clone());
+            gtk::glib::spawn_future_local(async move {
+                // Menu only items tend not to implement Activate at all
+                let activated = !item.item_is_menu
+                    && client
+                        .activate(&item, "Activate")
+                        .await
+                        .inspect_err(|err| log::debug!("Activate failed, showing the menu {err}"))
+                        .is_ok();
+                if !activated {
+                    show_sni_menu(&client, &item, &button).await;
+                }
+            });
+        });
+
+        let (middle_client, middle_item) = (client.clone(), item.clone());
+        let middle_click = gtk::GestureClick::builder().button(2).build();
+        middle_click.connect_released(move |_, _, _, _| {
+            let (client, item) = (middle_client.clone(), middle_item.clone());
+            gtk::glib::spawn_future_local(async move {
+                if let Err(err) = client.activate(&item, "SecondaryActivate").await {
+                    log::error!("Failed to activate {} {err}", item.service);
+                }
+            });
+        });
+        button.add_controller(middle_click);
+
+        let (right_client, right_item, right_button) =
+            (client.clone(), item.clone(), button.clone());
+        let right_click = gtk::GestureClick::builder().button(3).build();
+        right_click.connect_released(move |_, _, _, _| {
+            let (client, item, button) = (
+                right_client.clone(),
+                right_item.clone(),
+                right_button.clone(),
+            );
+            gtk::glib::spawn_future_local(async move {
+                if item.menu.is_some() {
+                    show_sni_menu(&client, &item, &button).await;
+                } else if let Err(err) = client.activate(&item, "ContextMenu").await {
+                    log::error!("Failed to open context menu of {} {err}", item.service);
+                }
+            });
+        });
+        button.add_controller(right_click);
+
+        let (scroll_client, scroll_item) = (client.clone(), item.clone());
+        // Touchpads scroll by fractions of a step, those add up until there's a whole one
+        let pending = Cell::new(0.);
+        let scroll = gtk::EventControllerScroll::new(gtk::EventControllerScrollFlags::VERTICAL);
+        scroll.connect_scroll(move |_, _, dy| {
+            let total = pending.get() + dy;
+            let steps = total.trunc();
+            pending.set(total - steps);
+            if steps != 0. {
+                let (client, item) = (scroll_client.clone(), scroll_item.clone());
+                gtk::glib::spawn_future_local(async move {
+                    if let Err(err) = client.scroll(&item, steps as i32).await {
+                        log::debug!("Failed to scroll {} {err}", item.service);
+                    }
+                });
+            }
+            gtk::glib::Propagation::Stop
+        });
+        button.add_controller(scroll);
+
+        button
+    }
+
+    fn icon(&self, item: &ItemState) -> gtk::Image {
+        let theme = gdk::Display::default().map(|display| gtk::IconTheme::for_display(&display));
+        if let Some(theme) = &theme {
+            let search_path = Path::new(&item.icon_theme_path);
+            if !item.icon_theme_path.is_empty()
+                && !theme.search_path().contains(&search_path.to_path_buf())
+            {
+                theme.add_search_path(search_path);
+            }
+        }
+        let has_icon = theme.is_some_and(|theme| theme.has_icon(&item.icon_name));
+
+        let image = if item.icon_name.starts_with('/') {
+            gtk::Image::from_file(&item.icon_name)
+        } else if !item.icon_name.is_empty() && (has_icon || item.icon_pixmaps.is_empty()) {
+            gtk::Image::from_icon_name(&item.icon_name)
+        } else {
+            match Self::texture(&item.icon_pixmaps, self.icon_size) {
+                Some(texture) => gtk::Image::from_paintable(Some(&texture)),
+                None => gtk::Image::from_icon_name("image-missing"),
+            }
+        };
+
+        image.set_pixel_size(self.icon_size);
+        image
+    }
+
+    /// Picks the smallest pixmap that is still big enough, otherwise the biggest one.
+    fn texture(pixmaps: &[Pixmap], size: i32) -> Option<gdk::MemoryTexture> {
+        let pixmap = pixmaps
+            .iter()
+            .filter(|pixmap| pixmap.width >= size)
+            .min_by_key(|pixmap| pixmap.width)
+            .or(pixmaps.iter().max_by_key(|pixmap| pixmap.width))?;
+
+        let stride = pixmap.width.max(0) as usize * 4;
+        if stride == 0 || pixmap.argb.len() < stride * pixmap.height.max(0) as usize {
+            return None;
+        }
+
+        Some(gdk::MemoryTexture::new(
+            pixmap.width,
+            pixmap.height,
+            gdk::MemoryFormat::A8r8g8b8,
+            &gtk::glib::Bytes::from(&pixmap.argb),
+            stride,
+        ))
+    }
+}
+
+async fn show_sni_menu(client: &Rc<SniClient>, item: &ItemState, button: &Button) {
+    let entries = match client.menu(item).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::error!("Failed to get the menu of {} {err}", item.service);
+            return;
+        }
+    };
+    // The items might have been shown anew while the app was answering
+    if button.root().is_none() {
+        return;
+    }
+
+    let actions = gio::SimpleActionGroup::new();
+    let menu = sni_menu_model(&entries, &actions, client, item);
+    button.insert_action_group("sni", Some(&actions));
+
+    let popover = gtk::PopoverMenu::from_model(Some(&menu));
+    popover.set_has_arrow(false);
+    popover.set_parent(button);
+    popover.connect_closed(|popover| popover.unparent());
+    popover.popup();
+}
+
+fn sni_menu_model(
+    entries: &[MenuItem],
+    actions: &gio::SimpleActionGroup,
+    client: &Rc<SniClient>,
+    item: &ItemState,
+) -> gio::Menu {
+    let menu = gio::Menu::new();
+    let mut section = gio::Menu::new();
+
+    for entry in entries {
+        if entry.separator {
+            menu.append_section(None, &section);
+            section = gio::Menu::new();
+        } else if !entry.children.is_empty() {
+            let submenu = sni_menu_model(&entry.children, actions, client, item);
+            section.append_submenu(Some(&entry.label), &submenu);
+        } else {
+            let name = format!("item-{}", entry.id);
+            // Stateful actions get rendered as check boxes
+            let action = match entry.toggled {
+                Some(toggled) => {
+                    gio::SimpleAction::new_stateful(&name, None, &toggled.to_variant())
+                }
+                None => gio::SimpleAction::new(&name, None),
+            };
+            action.set_enabled(entry.enabled);
+
+            let (client, item, id) = (client.clone(), item.clone(), entry.id);
+            action.connect_activate(move |_, _| {
+                let (client, item) = (client.clone(), item.clone());
+                gtk::glib::spawn_future_local(async move {
+                    if let Err(err) = client.menu_event(&item, id).await {
+                        log::error!("Failed to activate menu entry of {} {err}", item.service);
+                    }
+                });
+            });
+            actions.add_action(&action);
+            section.append(Some(&entry.label), Some(&format!("sni.{name}")));
+        }
+    }
+
+    menu.append_section(None, &section);
+    menu
+}
+
+/// XEmbed tray icons are plain X windows GTK4 can't host, so they live in a window of our
+/// own placed over one end of the bar. The bar content is pushed aside by a margin instead.
+pub struct Tray {
+    x11: Arc<X11Backend>,
+    container: u32,
+    icons: Vec<u32>,
+    /// Docked icons which are unmapped at the moment, they take no space.
+    hidden: HashSet<u32>,
+    /// Set once another tray manager took over, docking requests go to that one.
+    replaced: bool,
+    monitor: Monitor,
+    cfg: KrowbarConfig,
+    bar_box: gtk::CenterBox,
+}
+
+impl Tray {
+    pub fn new(
+        x11: Arc<X11Backend>,
+        bar_win: u32,
+        monitor: &Monitor,
+        cfg: &KrowbarConfig,
+        bar_box: &gtk::CenterBox,
+    ) -> anyhow::Result<Self> {
+        let container = x11.create_tray(bar_win, cfg)?;
+
+        Ok(Tray {
+            x11,
+            container,
+            icons: vec![],
+            hidden: HashSet::new(),
+            replaced: false,
+            monitor: monitor.clone(),
+            cfg: cfg.clone(),
+            bar_box: bar_box.clone(),
+        })
+    }
+
+    pub fn handle(&mut self, event: TrayEvent) {
+        match event {
+            TrayEvent::Dock(icon) => {
+                if self.replaced || self.icons.contains(&icon) {
+                    return;
+                }
+                // Added before docking, the icon might already be unmapped
+                self.icons.push(icon);
+                if let Err(err) = self.x11.dock_tray_icon(self.container, icon) {
+                    log::error!("Failed to dock tray icon {:?}", err);
+                    self.icons.retain(|docked| *docked != icon);
+                }
+            }
+            TrayEvent::Undock(icon) => self.forget(icon),
+            // Taken over by some other tray
+            TrayEvent::Reparent { icon, parent } if parent != self.container => self.forget(icon),
+            TrayEvent::Reparent { .. } => return,
+            TrayEvent::Shown(icon) if self.icons.contains(&icon) => {
+                self.hidden.remove(&icon);
+            }
+            TrayEvent::Hidden(icon) if self.icons.contains(&icon) => {
+                self.hidden.insert(icon);
+            }
+            TrayEvent::XembedInfo(icon) if self.icons.contains(&icon) => {
+                // Laid out again once the resulting map or unmap comes through
+                if let Err(err) = self.x11.map_tray_icon(icon) {
+                    log::error!("Failed to follow tray icon XEmbed info {:?}", err);
+                }
+                return;
+            }
+            TrayEvent::SelectionLost(owner) if owner == self.container => {
+                self.replaced = true;
+                for icon in std::mem::take(&mut self.icons) {
+                    if let Err(err) = self.x11.release_tray_icon(icon) {
+                        log::error!("Failed to release tray icon {:?}", err);
+                    }
+                }
+                self.hidden.clear();
+            }
+            _ => return,
+        }
+
+        self.layout();
+    }
+
+    /// Hands the docked icons back to the root window and gives up the tray, so the next tray
+    /// manager, e.g. the bar set up on reload, can take them in.
+    pub fn release(&self) {
+        for icon in &self.icons {
+            if let Err(err) = self.x11.release_tray_icon(*icon) {
+                log::error!("Failed to release tray icon {:?}", err);
+            }
+        }
+        if let Err(err) = self.x11.destroy_tray(self.container) {
+            log::error!("Failed to give up the system tray {:?}", err);
+        }
+    }
+
+    fn forget(&mut self, icon: u32) {
+        self.icons.retain(|docked| *docked != icon);
+        self.hidden.remove(&icon);
+    }
+
+    fn layout(&self) {
+        let shown: Vec<u32> = self
+            .icons
+            .iter()
+            .filter(|icon| !self.hidden.contains(icon))
+            .copied()
+            .collect();
+
+        match self
+            .x11
+            .layout_tray(self.container, &shown, &self.monitor, &self.cfg)
+        {
+            Ok(width) => match self.cfg.tray.position {
+                TrayPosition::Left => self.bar_box.set_margin_start(width.into()),
+                TrayPosition::Right => self.bar_box.set_margin_end(width.into()),
+            },
+            Err(err) => log::error!("Failed to lay out tray icons {:?}", err),
+        }
+    }
+}
@@ -1,57 +1,161 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::time::Duration;
 use x11rb::connection::Connection;
 use x11rb::properties::WmClass;
 use x11rb::protocol::randr;
+use x11rb::protocol::shape::{self, ConnectionExt as _};
+use x11rb::protocol::xkb::{self, ConnectionExt as _};
 use x11rb::protocol::xproto::*;
 use x11rb::protocol::xproto::{ConnectionExt, PropMode};
+use x11rb::protocol::Event;
 use x11rb::rust_connection::RustConnection;
 use x11rb::wrapper::ConnectionExt as _;
 
-use crate::config::KrowbarConfig;
-use crate::config::Position;
+use crate::config::{self, KrowbarConfig};
+use crate::config::{Position, TrayPosition};
+
+// Opcodes from the System Tray and XEmbed specs
+const SYSTEM_TRAY_REQUEST_DOCK: u32 = 0;
+const XEMBED_EMBEDDED_NOTIFY: u32 = 0;
+const XEMBED_VERSION: u32 = 0;
+// `_XEMBED_INFO` flag, set while the icon wants to be shown
+const XEMBED_MAPPED: u32 = 1 << 0;
 
 pub struct X11Backend {
     conn: RustConnection,
     pub root_window: u32,
+    screen_num: usize,
+    /// `_KROWBAR_Sn`, owned by the krowbar running on the screen.
+    instance_selection: u32,
     pub atoms: AtomCollection,
     pub monitors: Vec<Monitor>,
+    /// Whether the XKB extension got initialized, keyboard requests fail without it.
+    pub xkb: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Monitor {
     pub name: String,
+    pub primary: bool,
     x_offset: i16,
     y_offset: i16,
     width: u16,
-    #[allow(warnings)]
     height: u16,
 }
 
+/// A rectangle in root window coordinates.
+#[derive(Debug, Clone, Copy)]
+pub struct Geometry {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Monitor {
+    pub fn geometry(&self) -> Geometry {
+        Geometry {
+            x: self.x_offset,
+            y: self.y_offset,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Where the bar goes, centered horizontally between the margins.
+    pub fn bar_geometry(&self, bar: &config::Bar) -> Geometry {
+        let margin_x = bar.margin_x.resolve(self.width).min(self.width / 2);
+        let margin_y = self.bar_margin_y(bar);
+        let available = self.width - 2 * margin_x;
+        let width = bar.width.resolve(self.width).min(available);
+
+        let x = self.x_offset + (margin_x + (available - width) / 2) as i16;
+        let y = match bar.position {
+            Position::Top => self.y_offset + margin_y as i16,
+            Position::Bottom => {
+                self.y_offset + self.height as i16 - margin_y as i16 - bar.height as i16
+            }
+        };
+
+        Geometry {
+            x,
+            y,
+            width,
+            height: bar.height,
+        }
+    }
+
+    /// Space the bar takes from the monitor edge, margin included.
+    pub fn bar_reserved_space(&self, bar: &config::Bar) -> u16 {
+        bar.height + self.bar_margin_y(bar)
+    }
+
+    fn bar_margin_y(&self, bar: &config::Bar) -> u16 {
+        bar.margin_y
+            .resolve(self.height)
+            .min(self.height.saturating_sub(bar.height))
+    }
+}
+
 impl X11Backend {
-    pub fn new() -> Result<X11Backend> {
+    /// XKB is only set up `with_xkb`, i.e. when the keyboard widget or the autohide modifier
+    /// need it. Failing that leaves `xkb` unset, rather than failing the whole backend.
+    pub fn new(with_xkb: bool) -> Result<X11Backend> {
         let (conn, screen_num) = x11rb::connect(None)?;
 
         let screen = conn.setup().roots[screen_num].clone();
         let atoms = AtomCollection::new(&conn)?.reply()?;
+        let instance_selection = conn
+            .intern_atom(false, format!("_KROWBAR_S{screen_num}").as_bytes())?
+            .reply()?
+            .atom;
 
         let _ = randr::query_version(&conn, 1, 5)?.reply()?;
+        let xkb = with_xkb
+            && Self::init_xkb(&conn)
+                .inspect_err(|err| log::error!("Failed to init XKB {:?}", err))
+                .is_ok();
         let res = randr::get_screen_resources_current(&conn, screen.root)?.reply()?;
+        let primary = randr::get_output_primary(&conn, screen.root)?
+            .reply()?
+            .output;
 
         let monitors: Vec<Monitor> = res
             .outputs
             .into_iter()
-            .flat_map(|output| Self::output_to_monitor(output, &conn).transpose())
+            .flat_map(|output| Self::output_to_monitor(output, primary, &conn).transpose())
             .collect::<Result<Vec<Monitor>>>()?;
 
         Ok(X11Backend {
             conn,
             root_window: screen.root,
+            screen_num,
+            instance_selection,
             atoms,
             monitors,
+            xkb,
         })
     }
 
-    fn output_to_monitor(output: u32, conn: &RustConnection) -> Result<Option<Monitor>> {
+    /// Required before any other XKB request.
+    fn init_xkb(conn: &RustConnection) -> Result<()> {
+        let reply = conn.xkb_use_extension(1, 0)?.reply()?;
+        if !reply.supported {
+            return Err(anyhow!(
+                "XKB 1.0 is not supported, the server has {}.{}",
+                reply.server_major,
+                reply.server_minor
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn output_to_monitor(
+        output: u32,
+        primary: u32,
+        conn: &RustConnection,
+    ) -> Result<Option<Monitor>> {
         let info = randr::get_output_info(&conn, output, 0)?.reply()?;
 
         if info.connection == randr::Connection::CONNECTED {
@@ -59,6 +163,7 @@ impl X11Backend {
                 let crtc_info = randr::get_crtc_info(&conn, info.crtc, 0)?.reply()?;
                 Ok(Some(Monitor {
                     name: String::from_utf8(info.name)?,
+                    primary: output == primary,
                     x_offset: crtc_info.x,
                     y_offset: crtc_info.y,
                     width: crtc_info.width,
@@ -72,6 +177,83 @@ impl X11Backend {
         }
     }
 
+    /// Primary monitor as set via xrandr, or the first one if there is none.
+    pub fn primary_monitor(&self) -> Option<&Monitor> {
+        self.monitors
+            .iter()
+            .find(|monitor| monitor.primary)
+            .or(self.monitors.first())
+    }
+
+    /// Makes sure only one krowbar runs per screen, by owning the `_KROWBAR_Sn` selection. With
+    /// `replace` the running one is asked to exit, the way window managers replace each other.
+    pub fn acquire_instance_lock(&self, replace: bool) -> Result<()> {
+        let owner = self
+            .conn
+            .get_selection_owner(self.instance_selection)?
+            .reply()?
+            .owner;
+        if owner != x11rb::NONE && !replace {
+            return Err(anyhow!(
+                "krowbar is already running, use --replace to take over"
+            ));
+        }
+
+        let win = self.conn.generate_id()?;
+        self.conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            win,
+            self.root_window,
+            -1,
+            -1,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            x11rb::COPY_FROM_PARENT,
+            &CreateWindowAux::default(),
+        )?;
+        self.conn
+            .set_selection_owner(win, self.instance_selection, x11rb::CURRENT_TIME)?;
+        if self
+            .conn
+            .get_selection_owner(self.instance_selection)?
+            .reply()?
+            .owner
+            != win
+        {
+            return Err(anyhow!("Failed to acquire the krowbar selection"));
+        }
+
+        if owner == x11rb::NONE {
+            return Ok(());
+        }
+
+        // The replaced instance exits on SelectionClear, its window goes away with its connection
+        for _ in 0..50 {
+            if self.conn.get_geometry(owner)?.reply().is_err() {
+                log::info!("replaced the running krowbar");
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        log::warn!("replaced krowbar is still running");
+
+        Ok(())
+    }
+
+    /// Whether a compositor owns the `_NET_WM_CM_Sn` selection, i.e. translucency works.
+    pub fn is_composited(&self) -> Result<bool> {
+        let selection = self
+            .conn
+            .intern_atom(false, format!("_NET_WM_CM_S{}", self.screen_num).as_bytes())?
+            .reply()?
+            .atom;
+        let owner = self.conn.get_selection_owner(selection)?.reply()?.owner;
+
+        Ok(owner != x11rb::NONE)
+    }
+
     pub fn setup(&self, x11_win: u32, monitor: &Monitor, cfg: KrowbarConfig) -> Result<()> {
         self._setup(x11_win, monitor, &cfg)?;
         self._setup(x11_win, monitor, &cfg)?; // Some X11 race conditions here.
@@ -84,22 +266,470 @@ impl X11Backend {
         self.set_as_dock(x11_win)?;
         self.reparent(x11_win, self.root_window)?;
 
-        let (x, y) = match cfg.bar.position {
-            Position::Top => (monitor.x_offset, monitor.y_offset),
-            Position::Bottom => {
-                let x = monitor.x_offset;
-                let y = monitor.y_offset + monitor.height as i16 - cfg.bar.height as i16;
+        let bar = monitor.bar_geometry(&cfg.bar);
+        self.place_window_at(x11_win, bar.x.into(), bar.y.into())?;
+        self.resize_window(x11_win, bar.width.into(), bar.height.into())?;
 
-                (x, y)
-            }
+        Ok(())
+    }
+
+    /// Places a popup right under (or above, for bottom bars) the bar, with its right edge
+    /// aligned to `anchor_right`, which is relative to the bar.
+    pub fn place_popup(
+        &self,
+        popup_win: u32,
+        monitor: &Monitor,
+        cfg: &KrowbarConfig,
+        anchor_right: i32,
+        width: u16,
+        height: u16,
+    ) -> Result<()> {
+        let bar = monitor.bar_geometry(&cfg.bar);
+        // A popup wider than the monitor sticks to its left edge
+        let min_x = monitor.x_offset as i32;
+        let max_x = min_x + monitor.width.saturating_sub(width) as i32;
+        let x = (bar.x as i32 + anchor_right - width as i32)
+            .max(min_x)
+            .min(max_x);
+        let y = match cfg.bar.position {
+            Position::Top => bar.y as i32 + bar.height as i32,
+            Position::Bottom => bar.y as i32 - height as i32,
         };
 
-        self.place_window_at(x11_win, x.into(), y.into())?;
-        self.resize_window(x11_win, monitor.width.into(), cfg.bar.height.into())?;
+        self.place_window_at(popup_win, x, y)?;
+        self.resize_window(popup_win, width.into(), height.into())?;
 
         Ok(())
     }
 
+    /// Popups are not managed by the WM, so we have to stack and focus them ourselves.
+    pub fn set_as_popup(&self, win: u32) -> Result<()> {
+        self.conn.change_window_attributes(
+            win,
+            &ChangeWindowAttributesAux::default().override_redirect(1),
+        )?;
+        self.conn.change_property32(
+            PropMode::REPLACE,
+            win,
+            self.atoms._NET_WM_WINDOW_TYPE,
+            self.atoms.ATOM,
+            &[self.atoms._NET_WM_WINDOW_TYPE_DROPDOWN_MENU],
+        )?;
+
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    pub fn raise(&self, win: u32) -> Result<()> {
+        self.conn.configure_window(
+            win,
+            &ConfigureWindowAux {
+                stack_mode: Some(StackMode::ABOVE),
+                ..ConfigureWindowAux::default()
+            },
+        )?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    pub fn lower(&self, win: u32) -> Result<()> {
+        self.conn.configure_window(
+            win,
+            &ConfigureWindowAux {
+                stack_mode: Some(StackMode::BELOW),
+                ..ConfigureWindowAux::default()
+            },
+        )?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    pub fn raise_and_focus(&self, win: u32) -> Result<()> {
+        self.conn.configure_window(
+            win,
+            &ConfigureWindowAux {
+                stack_mode: Some(StackMode::ABOVE),
+                ..ConfigureWindowAux::default()
+            },
+        )?;
+        self.conn
+            .set_input_focus(InputFocus::PARENT, win, x11rb::CURRENT_TIME)?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    /// Becomes the system tray manager of the screen. Returns the container window, created
+    /// inside the bar window, which docked icons get reparented into.
+    pub fn create_tray(&self, bar_win: u32, cfg: &KrowbarConfig) -> Result<u32> {
+        let selection = self
+            .conn
+            .intern_atom(
+                false,
+                format!("_NET_SYSTEM_TRAY_S{}", self.screen_num).as_bytes(),
+            )?
+            .reply()?
+            .atom;
+
+        let owner = self.conn.get_selection_owner(selection)?.reply()?.owner;
+        if owner != x11rb::NONE {
+            return Err(anyhow!("Another system tray is already running"));
+        }
+
+        let background = parse_color(&cfg.theme.bg).unwrap_or_else(|| {
+            log::warn!(
+                "tray background {:?} isn't #rrggbb, using black",
+                cfg.theme.bg
+            );
+            0
+        });
+        let container = self.conn.generate_id()?;
+        self.conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            container,
+            bar_win,
+            0,
+            0,
+            1,
+            cfg.bar.height,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            x11rb::COPY_FROM_PARENT,
+            &CreateWindowAux::default().background_pixel(background),
+        )?;
+        self.conn.change_property32(
+            PropMode::REPLACE,
+            container,
+            self.atoms._NET_SYSTEM_TRAY_ORIENTATION,
+            self.atoms.CARDINAL,
+            &[0], // Horizontal
+        )?;
+
+        self.conn
+            .set_selection_owner(container, selection, x11rb::CURRENT_TIME)?;
+        if self.conn.get_selection_owner(selection)?.reply()?.owner != container {
+            return Err(anyhow!("Failed to acquire the system tray selection"));
+        }
+
+        // Lets the clients started before us know that they can dock now
+        let manager = ClientMessageEvent::new(
+            32,
+            self.root_window,
+            self.atoms.MANAGER,
+            [x11rb::CURRENT_TIME, selection, container, 0, 0],
+        );
+        self.conn.send_event(
+            false,
+            self.root_window,
+            EventMask::STRUCTURE_NOTIFY,
+            manager,
+        )?;
+
+        self.conn.flush()?;
+        Ok(container)
+    }
+
+    pub fn dock_tray_icon(&self, container: u32, icon: u32) -> Result<()> {
+        // To find out when the icon goes away, hides itself or changes its `_XEMBED_INFO`
+        self.conn.change_window_attributes(
+            icon,
+            &ChangeWindowAttributesAux::default()
+                .event_mask(EventMask::STRUCTURE_NOTIFY | EventMask::PROPERTY_CHANGE),
+        )?;
+        // Icons get reparented back to the root window, if we die
+        self.conn.change_save_set(SetMode::INSERT, icon)?;
+        self.conn.reparent_window(icon, container, 0, 0)?;
+
+        let embedded = ClientMessageEvent::new(
+            32,
+            icon,
+            self.atoms._XEMBED,
+            [
+                x11rb::CURRENT_TIME,
+                XEMBED_EMBEDDED_NOTIFY,
+                0,
+                container,
+                XEMBED_VERSION,
+            ],
+        );
+        self.conn
+            .send_event(false, icon, EventMask::NO_EVENT, embedded)?;
+        self.map_tray_icon(icon)?;
+
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    /// Maps or unmaps the icon, following the mapped flag of its `_XEMBED_INFO`. Icons without
+    /// one are always shown.
+    pub fn map_tray_icon(&self, icon: u32) -> Result<()> {
+        let info = self
+            .conn
+            .get_property(
+                false,
+                icon,
+                self.atoms._XEMBED_INFO,
+                self.atoms._XEMBED_INFO,
+                0,
+                2,
+            )?
+            .reply()?;
+        let mapped = info
+            .value32()
+            .and_then(|mut info| info.nth(1))
+            .is_none_or(|flags| flags & XEMBED_MAPPED != 0);
+
+        if mapped {
+            self.conn.map_window(icon)?;
+        } else {
+            self.conn.unmap_window(icon)?;
+        }
+
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    /// Hands the icon back to the root window, so it can dock with the new tray manager.
+    pub fn release_tray_icon(&self, icon: u32) -> Result<()> {
+        self.conn.change_window_attributes(
+            icon,
+            &ChangeWindowAttributesAux::default().event_mask(EventMask::NO_EVENT),
+        )?;
+        self.conn.unmap_window(icon)?;
+        self.conn.reparent_window(icon, self.root_window, 0, 0)?;
+        self.conn.change_save_set(SetMode::DELETE, icon)?;
+
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    /// The tray selection goes away along with the container owning it.
+    pub fn destroy_tray(&self, container: u32) -> Result<()> {
+        self.conn.destroy_window(container)?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    /// Lines the icons up inside the container and moves it to the configured end of the
+    /// bar. Returns the width taken by the tray.
+    pub fn layout_tray(
+        &self,
+        container: u32,
+        icons: &[u32],
+        monitor: &Monitor,
+        cfg: &KrowbarConfig,
+    ) -> Result<u16> {
+        if icons.is_empty() {
+            self.conn.unmap_window(container)?;
+            self.conn.flush()?;
+            return Ok(0);
+        }
+
+        let padding = cfg.tray.icon_padding;
+        let icon_size = cfg.bar.height.saturating_sub(2 * padding).max(1);
+        let width = icons.len() as u16 * (icon_size + padding) + padding;
+
+        for (idx, icon) in icons.iter().enumerate() {
+            self.conn.configure_window(
+                *icon,
+                &ConfigureWindowAux {
+                    x: Some((padding + idx as u16 * (icon_size + padding)).into()),
+                    y: Some(padding.into()),
+                    width: Some(icon_size.into()),
+                    height: Some(icon_size.into()),
+                    ..ConfigureWindowAux::default()
+                },
+            )?;
+        }
+
+        let x = match cfg.tray.position {
+            TrayPosition::Left => 0,
+            TrayPosition::Right => monitor.bar_geometry(&cfg.bar).width.saturating_sub(width),
+        };
+        self.conn.configure_window(
+            container,
+            &ConfigureWindowAux {
+                x: Some(x.into()),
+                y: Some(0),
+                width: Some(width.into()),
+                height: Some(cfg.bar.height.into()),
+                ..ConfigureWindowAux::default()
+            },
+        )?;
+        self.conn.map_window(container)?;
+
+        self.conn.flush()?;
+        Ok(width)
+    }
+
+    /// Subscribes to keyboard group (layout) and lock changes.
+    pub fn watch_keyboard(&self) -> Result<()> {
+        let details = xkb::SelectEventsAux::new()
+            .state_notify(xkb::SelectEventsAuxStateNotify {
+                affect_state: xkb::StatePart::GROUP_STATE | xkb::StatePart::MODIFIER_LOCK,
+                state_details: xkb::StatePart::GROUP_STATE | xkb::StatePart::MODIFIER_LOCK,
+            })
+            // Layouts changed with setxkbmap
+            .names_notify(xkb::SelectEventsAuxNamesNotify {
+                affect_names: xkb::NameDetail::GROUP_NAMES,
+                names_details: xkb::NameDetail::GROUP_NAMES,
+            });
+        self.conn.xkb_select_events(
+            xkb::ID::USE_CORE_KBD.into(),
+            xkb::EventType::from(0u16),
+            xkb::EventType::from(0u16),
+            xkb::MapPart::from(0u16),
+            xkb::MapPart::from(0u16),
+            &details,
+        )?;
+
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    /// Subscribes to the modifiers held down, they come as `X11Event::ModifiersChange`.
+    pub fn watch_modifiers(&self) -> Result<()> {
+        let details = xkb::SelectEventsAux::new().state_notify(xkb::SelectEventsAuxStateNotify {
+            affect_state: xkb::StatePart::MODIFIER_STATE,
+            state_details: xkb::StatePart::MODIFIER_STATE,
+        });
+        self.conn.xkb_select_events(
+            xkb::ID::USE_CORE_KBD.into(),
+            xkb::EventType::from(0u16),
+            xkb::EventType::from(0u16),
+            xkb::MapPart::from(0u16),
+            xkb::MapPart::from(0u16),
+            &details,
+        )?;
+
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    pub fn keyboard_state(&self) -> Result<KeyboardState> {
+        let state = self
+            .conn
+            .xkb_get_state(xkb::ID::USE_CORE_KBD.into())?
+            .reply()?;
+        let group_atoms = self
+            .conn
+            .xkb_get_names(xkb::ID::USE_CORE_KBD.into(), xkb::NameDetail::GROUP_NAMES)?
+            .reply()?
+            .value_list
+            .groups
+            .unwrap_or_default();
+
+        let group_names = group_atoms
+            .into_iter()
+            .map(|atom| {
+                let name = self.conn.get_atom_name(atom)?.reply()?.name;
+                Ok(String::from_utf8_lossy(&name).into_owned())
+            })
+            .collect::<Result<Vec<String>>>()?;
+
+        // Rules, model, layouts, variants and options, separated by NULs
+        let rules = self
+            .conn
+            .get_property(
+                false,
+                self.root_window,
+                self.atoms._XKB_RULES_NAMES,
+                self.atoms.STRING,
+                0,
+                1024,
+            )?
+            .reply()?
+            .value;
+        let layouts = rules
+            .split(|byte| *byte == 0)
+            .nth(2)
+            .map(|layouts| {
+                String::from_utf8_lossy(layouts)
+                    .split(',')
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(KeyboardState {
+            group: state.group.into(),
+            group_names,
+            layouts,
+            caps_lock: state.locked_mods.contains(ModMask::LOCK),
+            // XXX: Num Lock is bound to Mod2 on pretty much any setup, we don't resolve it
+            num_lock: state.locked_mods.contains(ModMask::M2),
+        })
+    }
+
+    /// Locks the next keyboard group, wrapping around after the last one.
+    pub fn next_keyboard_group(&self) -> Result<()> {
+        let keyboard = self.keyboard_state()?;
+        let group_count = keyboard.group_names.len().max(1) as u8;
+        let next = (keyboard.group + 1) % group_count;
+
+        self.conn.xkb_latch_lock_state(
+            xkb::ID::USE_CORE_KBD.into(),
+            ModMask::from(0u16),
+            ModMask::from(0u16),
+            true,
+            xkb::Group::from(next),
+            ModMask::from(0u16),
+            false,
+            0,
+        )?;
+
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    /// Blocks, waiting for the X events we subscribed to, so should run on its own thread.
+    pub fn listen_to_events(&self, on_event: impl Fn(X11Event)) -> Result<()> {
+        loop {
+            match self.conn.wait_for_event()? {
+                Event::XkbStateNotify(event) => {
+                    let layout_parts = xkb::StatePart::GROUP_STATE | xkb::StatePart::MODIFIER_LOCK;
+                    if event.changed.intersects(layout_parts) {
+                        on_event(X11Event::KeyboardChange);
+                    }
+                    if event.changed.contains(xkb::StatePart::MODIFIER_STATE) {
+                        on_event(X11Event::ModifiersChange(event.mods.into()));
+                    }
+                }
+                Event::XkbNamesNotify(_) => on_event(X11Event::KeyboardChange),
+                Event::ClientMessage(event)
+                    if event.type_ == self.atoms._NET_SYSTEM_TRAY_OPCODE =>
+                {
+                    let [_, opcode, icon, _, _] = event.data.as_data32();
+                    if opcode == SYSTEM_TRAY_REQUEST_DOCK {
+                        on_event(X11Event::Tray(TrayEvent::Dock(icon)));
+                    }
+                }
+                Event::DestroyNotify(event) => {
+                    on_event(X11Event::Tray(TrayEvent::Undock(event.window)))
+                }
+                Event::MapNotify(event) => on_event(X11Event::Tray(TrayEvent::Shown(event.window))),
+                Event::UnmapNotify(event) => {
+                    on_event(X11Event::Tray(TrayEvent::Hidden(event.window)))
+                }
+                Event::PropertyNotify(event) if event.atom == self.atoms._XEMBED_INFO => {
+                    on_event(X11Event::Tray(TrayEvent::XembedInfo(event.window)))
+                }
+                Event::ReparentNotify(event) => on_event(X11Event::Tray(TrayEvent::Reparent {
+                    icon: event.window,
+                    parent: event.parent,
+                })),
+                Event::SelectionClear(event) if event.selection == self.instance_selection => {
+                    log::info!("replaced by another krowbar");
+                    on_event(X11Event::Replaced)
+                }
+                Event::SelectionClear(event) => {
+                    log::info!("system tray selection taken over");
+                    on_event(X11Event::Tray(TrayEvent::SelectionLost(event.owner)))
+                }
+                event => log::debug!("ignored X event {:?}", event),
+            }
+        }
+    }
+
     pub fn get_wm_class(&self, win: u32) -> Result<Option<String>> {
         let wm_class = WmClass::from_reply(
             self.conn
@@ -111,6 +741,55 @@ impl X11Backend {
         Ok(wm_class)
     }
 
+    /// Reserves `size` pixels along the bar's edge of the monitor. `_NET_WM_STRUT` is set too,
+    /// for WMs that don't know the partial one.
+    pub fn set_strut(
+        &self,
+        win: u32,
+        monitor: &Monitor,
+        position: &Position,
+        size: u16,
+    ) -> Result<()> {
+        let screen = &self.conn.setup().roots[self.screen_num];
+        let start_x = monitor.x_offset as u32;
+        let end_x = (start_x + monitor.width as u32).saturating_sub(1);
+
+        // Struts are relative to the root window edges, not to the monitor
+        let mut strut = [0u32; 12];
+        match position {
+            Position::Top => {
+                strut[2] = monitor.y_offset as u32 + size as u32;
+                strut[8] = start_x;
+                strut[9] = end_x;
+            }
+            Position::Bottom => {
+                let monitor_bottom = monitor.y_offset as u32 + monitor.height as u32;
+                strut[3] =
+                    (screen.height_in_pixels as u32).saturating_sub(monitor_bottom) + size as u32;
+                strut[10] = start_x;
+                strut[11] = end_x;
+            }
+        }
+
+        self.conn.change_property32(
+            PropMode::REPLACE,
+            win,
+            self.atoms._NET_WM_STRUT_PARTIAL,
+            self.atoms.CARDINAL,
+            &strut,
+        )?;
+        self.conn.change_property32(
+            PropMode::REPLACE,
+            win,
+            self.atoms._NET_WM_STRUT,
+            self.atoms.CARDINAL,
+            &strut[..4],
+        )?;
+
+        self.conn.flush()?;
+        Ok(())
+    }
+
     pub fn reparent(&self, win: u32, new_parent_win: u32) -> Result<()> {
         self.conn.reparent_window(win, new_parent_win, 0, 0)?;
         self.conn.flush()?;
@@ -144,6 +823,29 @@ impl X11Backend {
         Ok(())
     }
 
+    /// Clips the window, about to be placed at `x`, `y`, to `bounds`, so nothing of it outside
+    /// of them is drawn or takes input.
+    pub fn clip_window(&self, win: u32, x: i32, y: i32, bounds: Geometry) -> Result<()> {
+        let visible = Rectangle {
+            x: (i32::from(bounds.x) - x) as i16,
+            y: (i32::from(bounds.y) - y) as i16,
+            width: bounds.width,
+            height: bounds.height,
+        };
+        for kind in [shape::SK::BOUNDING, shape::SK::INPUT] {
+            self.conn.shape_rectangles(
+                shape::SO::SET,
+                kind,
+                ClipOrdering::UNSORTED,
+                win,
+                0,
+                0,
+                &[visible],
+            )?;
+        }
+        Ok(())
+    }
+
     pub fn place_window_at(&self, win: u32, x: i32, y: i32) -> Result<()> {
         self.conn.configure_window(
             win,
@@ -158,11 +860,72 @@ impl X11Backend {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyboardState {
+    pub group: u8,
+    /// Descriptive group names, e.g. `English (US)`.
+    pub group_names: Vec<String>,
+    /// Layouts as passed to setxkbmap, e.g. `us`, one per group.
+    pub layouts: Vec<String>,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum X11Event {
+    Tray(TrayEvent),
+    /// Keyboard layout or lock state changed.
+    KeyboardChange,
+    /// Modifiers held down, as a `ModMask`.
+    ModifiersChange(u16),
+    /// Another krowbar took over, see `acquire_instance_lock`.
+    Replaced,
+}
+
+#[derive(Debug, Clone)]
+pub enum TrayEvent {
+    Dock(u32),
+    Undock(u32),
+    Reparent {
+        icon: u32,
+        parent: u32,
+    },
+    /// Mapped, by us or by the icon itself.
+    Shown(u32),
+    Hidden(u32),
+    /// The icon changed its `_XEMBED_INFO`, e.g. to hide itself.
+    XembedInfo(u32),
+    /// Another tray manager took the selection away from the container.
+    SelectionLost(u32),
+}
+
+/// Parses `#rrggbb` into an opaque pixel value. The alpha of `#rrggbbaa` is dropped, and logged,
+/// as the tray container doesn't blend with what's under the bar.
+fn parse_color(hex: &str) -> Option<u32> {
+    let hex = hex.strip_prefix('#')?;
+    let rgb = u32::from_str_radix(hex.get(..6)?, 16).ok()?;
+    let alpha = hex.get(6..).unwrap_or_default();
+    if !alpha.is_empty() && !alpha.eq_ignore_ascii_case("ff") {
+        log::warn!("alpha of #{hex} is dropped, the tray stays opaque");
+    }
+    Some(0xff000000 | rgb)
+}
+
 x11rb::atom_manager! {
     pub AtomCollection: AtomCollectionCookie {
         _NET_WM_WINDOW_TYPE,
         _NET_WM_WINDOW_TYPE_DOCK,
+        _NET_WM_WINDOW_TYPE_DROPDOWN_MENU,
         _NET_WM_NAME,
+        _NET_WM_STRUT,
+        _NET_WM_STRUT_PARTIAL,
+        _NET_SYSTEM_TRAY_OPCODE,
+        _NET_SYSTEM_TRAY_ORIENTATION,
+        _XEMBED,
+        _XEMBED_INFO,
+        _XKB_RULES_NAMES,
+        MANAGER,
+        CARDINAL,
         WM_NAME,
         UTF8_STRING,
         ATOM,
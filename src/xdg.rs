@@ -0,0 +1,57 @@
+//! XDG base directories, see https://specifications.freedesktop.org/basedir-spec/latest/
+
+use std::path::PathBuf;
+
+pub fn home() -> Option<PathBuf> {
+    absolute_var("HOME")
+}
+
+/// `$XDG_CONFIG_HOME/krowbar`, `~/.config/krowbar` by default.
+pub fn config_dir() -> PathBuf {
+    base_dir("XDG_CONFIG_HOME", ".config").join("krowbar")
+}
+
+/// `$XDG_CONFIG_DIRS`, searched after `config_dir`, e.g. for a config shared by all users.
+pub fn system_config_dirs() -> Vec<PathBuf> {
+    let dirs = std::env::var("XDG_CONFIG_DIRS").unwrap_or_default();
+    let dirs = match dirs.trim() {
+        "" => "/etc/xdg",
+        dirs => dirs,
+    };
+
+    dirs.split(':')
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join("krowbar"))
+        .collect()
+}
+
+/// `$XDG_STATE_HOME/krowbar`, `~/.local/state/krowbar` by default. Logs go here.
+pub fn state_dir() -> PathBuf {
+    base_dir("XDG_STATE_HOME", ".local/state").join("krowbar")
+}
+
+/// `$XDG_RUNTIME_DIR/krowbar.{extension}` for sockets and such. Without a runtime dir the file
+/// goes to the temp dir, named after the user, so users sharing a machine don't clash.
+pub fn runtime_file(extension: &str) -> PathBuf {
+    match absolute_var("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => runtime_dir.join(format!("krowbar.{extension}")),
+        None => {
+            let user = std::env::var("USER").unwrap_or_default();
+            std::env::temp_dir().join(format!("krowbar-{user}.{extension}"))
+        }
+    }
+}
+
+fn base_dir(var: &str, default: &str) -> PathBuf {
+    absolute_var(var)
+        .or_else(|| home().map(|home| home.join(default)))
+        .expect("Neither $HOME nor $XDG_* base dirs are set")
+}
+
+/// The spec says relative paths are invalid and should be ignored.
+fn absolute_var(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+}